@@ -0,0 +1,103 @@
+//! Runs the same benches against every backend, driving the registries by hand:
+//! starting and stopping timers, expiring a tick where every timer or no timer expires,
+//! and a common workload of timers spread over a few seconds that are all expired.
+
+use std::time::Duration;
+
+use accuracy::{delays, Backend, PriorityQueue};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+const NUM_TIMERS: usize = 1000;
+const MAX_DELAY_SECS: u64 = 3;
+
+fn with_timers<B: Backend>(delays: &[Duration]) -> (B, Vec<B::Handle>) {
+    let registry = B::new();
+    let handles = delays
+        .iter()
+        .enumerate()
+        .map(|(i, &delay)| registry.start_timer(i, delay, || {}))
+        .collect();
+    (registry, handles)
+}
+
+fn bench<B: Backend>(c: &mut Criterion) {
+    let delays = delays(NUM_TIMERS, MAX_DELAY_SECS);
+    let mut group = c.benchmark_group(B::NAME);
+
+    group.bench_function("start_timer", |b| {
+        b.iter_batched(
+            B::new,
+            |registry| {
+                for (i, &delay) in delays.iter().enumerate() {
+                    registry.start_timer(i, delay, || {});
+                }
+                registry
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("stop_timer", |b| {
+        b.iter_batched(
+            || with_timers::<B>(&delays),
+            |(registry, handles)| {
+                for handle in &handles {
+                    registry.stop_timer(handle);
+                }
+                registry
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("expire_timers/all_expire", |b| {
+        b.iter_batched(
+            || with_timers::<B>(&[Duration::from_secs(1); NUM_TIMERS]).0,
+            |mut registry| {
+                registry.tick();
+                registry
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("expire_timers/none_expire", |b| {
+        b.iter_batched(
+            || with_timers::<B>(&[Duration::from_secs(2); NUM_TIMERS]).0,
+            |mut registry| {
+                registry.tick();
+                registry
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // The baseline every backend is compared on.
+    group.bench_function("start_and_expire", |b| {
+        b.iter_batched(
+            B::new,
+            |mut registry| {
+                for (i, &delay) in delays.iter().enumerate() {
+                    registry.start_timer(i, delay, || {});
+                }
+                for _ in 0..MAX_DELAY_SECS {
+                    registry.tick();
+                }
+                registry
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench::<straightforward::Registry>,
+    bench::<PriorityQueue>,
+    bench::<timing_wheels::Registry>,
+    bench::<hash_table_with_sorted_timers_in_each_bucket::Registry>,
+    bench::<hierarchical_timer_wheels::Registry>
+);
+criterion_main!(benches);
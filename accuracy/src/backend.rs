@@ -0,0 +1,178 @@
+//! A common interface over every backend, driven by hand, for the measurements and benches.
+
+use std::time::{Duration, Instant};
+
+/// A registry with no background thread, ticked a second at a time by hand.
+pub trait Backend {
+    const NAME: &'static str;
+
+    type Handle;
+
+    fn new() -> Self;
+
+    /// Starts the `i`th timer of a run.
+    fn start_timer(
+        &self,
+        i: usize,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Self::Handle;
+
+    fn stop_timer(&self, handle: &Self::Handle);
+
+    /// Advances the registry by a second and runs the actions of the timers that expired.
+    fn tick(&mut self);
+}
+
+impl Backend for straightforward::Registry {
+    const NAME: &'static str = "straightforward";
+
+    type Handle = straightforward::TimerId;
+
+    fn new() -> Self {
+        Self::without_bookkeeping()
+    }
+
+    fn start_timer(
+        &self,
+        i: usize,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Self::Handle {
+        let id = straightforward::TimerId(i as u64);
+        self.start_timer(id, expires_in, expire_action);
+        id
+    }
+
+    fn stop_timer(&self, handle: &Self::Handle) {
+        self.stop_timer(*handle);
+    }
+
+    fn tick(&mut self) {
+        self.expire_timers();
+    }
+}
+
+/// Expires timers by instant, so it keeps the instant it is at.
+pub struct PriorityQueue {
+    registry: priority_queue::Registry,
+    now: Instant,
+}
+
+impl Backend for PriorityQueue {
+    const NAME: &'static str = "priority_queue";
+
+    type Handle = priority_queue::TimerId;
+
+    fn new() -> Self {
+        Self {
+            registry: priority_queue::Registry::without_bookkeeping(),
+            now: Instant::now(),
+        }
+    }
+
+    fn start_timer(
+        &self,
+        i: usize,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Self::Handle {
+        let id = priority_queue::TimerId(i as u64);
+        self.registry
+            .start_timer(id, self.now + expires_in, expire_action)
+            .unwrap();
+        id
+    }
+
+    fn stop_timer(&self, handle: &Self::Handle) {
+        self.registry.stop_timer(*handle).unwrap();
+    }
+
+    fn tick(&mut self) {
+        self.now += Duration::from_secs(1);
+        self.registry.expire_timers(self.now).unwrap();
+    }
+}
+
+impl Backend for timing_wheels::Registry {
+    const NAME: &'static str = "timing_wheels";
+
+    type Handle = timing_wheels::TimerHandle;
+
+    fn new() -> Self {
+        Self::builder().build_registry()
+    }
+
+    fn start_timer(
+        &self,
+        _: usize,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Self::Handle {
+        self.start_timer(expires_in, expire_action)
+    }
+
+    fn stop_timer(&self, handle: &Self::Handle) {
+        self.stop_timer(handle);
+    }
+
+    fn tick(&mut self) {
+        self.expire_timers();
+    }
+}
+
+impl Backend for hash_table_with_sorted_timers_in_each_bucket::Registry {
+    const NAME: &'static str = "hash_table_with_sorted_timers_in_each_bucket";
+
+    type Handle = hash_table_with_sorted_timers_in_each_bucket::TimerHandle;
+
+    fn new() -> Self {
+        Self::without_bookkeeping()
+    }
+
+    fn start_timer(
+        &self,
+        _: usize,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Self::Handle {
+        self.start_timer(expires_in, expire_action)
+    }
+
+    fn stop_timer(&self, handle: &Self::Handle) {
+        self.stop_timer(handle);
+    }
+
+    fn tick(&mut self) {
+        self.expire_timers();
+    }
+}
+
+impl Backend for hierarchical_timer_wheels::Registry {
+    const NAME: &'static str = "hierarchical_timer_wheels";
+
+    type Handle = hierarchical_timer_wheels::TimerHandle;
+
+    fn new() -> Self {
+        Self::builder()
+            .build_registry()
+            .expect("the default level sizes are valid")
+    }
+
+    fn start_timer(
+        &self,
+        _: usize,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Self::Handle {
+        self.start_timer(expires_in, expire_action)
+    }
+
+    fn stop_timer(&self, handle: &Self::Handle) {
+        self.stop_timer(handle);
+    }
+
+    fn tick(&mut self) {
+        self.expire_timers();
+    }
+}
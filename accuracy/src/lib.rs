@@ -0,0 +1,158 @@
+//! Measures how close to their deadline the timers of a backend fire.
+//!
+//! Every backend is ticked by hand instead of in real time,
+//! so measurements are deterministic, take no longer than the work they do,
+//! and lateness is always a whole number of ticks.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+mod backend;
+
+pub use backend::{Backend, PriorityQueue};
+
+/// How late the timers of a backend fired, in milliseconds.
+/// Timers that fired early count as negative lateness.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub backend: &'static str,
+    /// The number of timers that did not fire before the measurement gave up on them.
+    pub missed: usize,
+    pub p50: i64,
+    pub p99: i64,
+    pub max: i64,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<46} p50={:>5}ms p99={:>5}ms max={:>5}ms missed={}",
+            self.backend, self.p50, self.p99, self.max, self.missed
+        )
+    }
+}
+
+/// Returns `count` whole second delays spread evenly between 1 and `max_secs` seconds,
+/// so every backend can represent them.
+pub fn delays(count: usize, max_secs: u64) -> Vec<Duration> {
+    (0..count as u64)
+        .map(|i| Duration::from_secs(1 + i % max_secs))
+        .collect()
+}
+
+/// Starts a timer for every delay in `delays` on a new `B`
+/// and ticks it until `grace_ticks` ticks after the longest delay, giving up on the timers
+/// that did not fire by then.
+pub fn measure<B: Backend>(delays: &[Duration], grace_ticks: u64) -> Report {
+    let mut registry = B::new();
+    let current_tick = Arc::new(AtomicU64::new(0));
+    let lateness = Arc::new(Mutex::new(Vec::with_capacity(delays.len())));
+
+    for (i, &delay) in delays.iter().enumerate() {
+        let current_tick = Arc::clone(&current_tick);
+        let lateness = Arc::clone(&lateness);
+
+        registry.start_timer(i, delay, move || {
+            let fired_at = current_tick.load(Ordering::SeqCst) as i64 * 1000;
+            lateness
+                .lock()
+                .unwrap()
+                .push(fired_at - delay.as_millis() as i64);
+        });
+    }
+
+    let longest_delay = delays.iter().max().copied().unwrap_or_default();
+    let last_tick =
+        longest_delay.as_secs() + u64::from(longest_delay.subsec_nanos() > 0) + grace_ticks;
+
+    for tick in 1..=last_tick {
+        current_tick.store(tick, Ordering::SeqCst);
+        registry.tick();
+    }
+    drop(registry);
+
+    let mut lateness = std::mem::take(&mut *lateness.lock().unwrap());
+    lateness.sort_unstable();
+
+    Report {
+        backend: B::NAME,
+        missed: delays.len() - lateness.len(),
+        p50: percentile(&lateness, 50),
+        p99: percentile(&lateness, 99),
+        max: lateness.last().copied().unwrap_or_default(),
+    }
+}
+
+/// Returns the `p`th percentile of `sorted`, or 0 if it is empty.
+fn percentile(sorted: &[i64], p: usize) -> i64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fires every timer as soon as it starts.
+    struct Immediate;
+
+    impl Backend for Immediate {
+        const NAME: &'static str = "immediate";
+
+        type Handle = ();
+
+        fn new() -> Self {
+            Immediate
+        }
+
+        fn start_timer(
+            &self,
+            _: usize,
+            _: Duration,
+            expire_action: impl FnOnce() + Send + Sync + 'static,
+        ) {
+            expire_action();
+        }
+
+        fn stop_timer(&self, _: &()) {}
+
+        fn tick(&mut self) {}
+    }
+
+    /// Never fires a timer.
+    struct Never;
+
+    impl Backend for Never {
+        const NAME: &'static str = "never";
+
+        type Handle = ();
+
+        fn new() -> Self {
+            Never
+        }
+
+        fn start_timer(&self, _: usize, _: Duration, _: impl FnOnce() + Send + Sync + 'static) {}
+
+        fn stop_timer(&self, _: &()) {}
+
+        fn tick(&mut self) {}
+    }
+
+    #[test]
+    fn percentiles_of_the_timers_that_fired() {
+        let report = measure::<Immediate>(&delays(100, 1), 0);
+        assert_eq!(0, report.missed);
+        assert_eq!(-1000, report.p50, "{report}");
+        assert_eq!(-1000, report.max, "{report}");
+
+        let report = measure::<Never>(&delays(10, 1), 0);
+        assert_eq!(10, report.missed);
+    }
+}
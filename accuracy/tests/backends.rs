@@ -0,0 +1,30 @@
+use accuracy::{delays, measure, Backend, PriorityQueue, Report};
+
+const NUM_TIMERS: usize = 1000;
+const MAX_DELAY_SECS: u64 = 3;
+/// Backends can fire a tick after the deadline, so they get a couple more to fire every timer.
+const GRACE_TICKS: u64 = 2;
+
+fn measure_backend<B: Backend>() -> Report {
+    measure::<B>(&delays(NUM_TIMERS, MAX_DELAY_SECS), GRACE_TICKS)
+}
+
+/// Run with `--nocapture` to see the numbers.
+#[test]
+fn every_backend_fires_every_timer() {
+    let reports = [
+        measure_backend::<straightforward::Registry>(),
+        measure_backend::<PriorityQueue>(),
+        measure_backend::<timing_wheels::Registry>(),
+        measure_backend::<hash_table_with_sorted_timers_in_each_bucket::Registry>(),
+        measure_backend::<hierarchical_timer_wheels::Registry>(),
+    ];
+
+    for report in &reports {
+        println!("{report}");
+    }
+
+    for report in &reports {
+        assert_eq!(0, report.missed, "{report}");
+    }
+}
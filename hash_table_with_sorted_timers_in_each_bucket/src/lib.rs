@@ -2,8 +2,10 @@
 #![feature(drain_filter)]
 
 use std::{
-    sync::{Arc, Mutex, Weak},
-    time::Duration,
+    collections::{BTreeMap, HashMap},
+    panic::AssertUnwindSafe,
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 struct DoublyLinkedList<T> {
@@ -86,7 +88,8 @@ impl<T> DoublyLinkedList<T> {
         }
     }
 
-    fn insert_after(&mut self, node: *mut Node<T>, value: T) {
+    /// Returns the node `value` was placed in.
+    fn insert_after(&mut self, node: *mut Node<T>, value: T) -> *mut Node<T> {
         let new_node = Box::into_raw(Box::new(Node {
             value: Some(value),
             previous: std::ptr::null_mut(),
@@ -99,9 +102,11 @@ impl<T> DoublyLinkedList<T> {
             (*new_node).previous = node;
             (*node).next = new_node;
 
-            (*next).previous = node;
+            (*next).previous = new_node;
             (*new_node).next = next;
         }
+
+        new_node
     }
 
     fn iter_mut(&mut self) -> IterMut<'_, T> {
@@ -127,7 +132,8 @@ impl<'a, T> Iterator for IterMut<'a, T> {
     type Item = *mut Node<T>;
 
     fn next(&mut self) -> Option<*mut Node<T>> {
-        if self.current.is_null() {
+        // The dummy tail is the only node without a next node.
+        if unsafe { (*self.current).next.is_null() } {
             None
         } else {
             let node = self.current;
@@ -158,158 +164,534 @@ struct Node<T> {
 }
 
 pub struct Registry {
+    /// When the wheel was at tick 0.
+    started_at: Instant,
+    /// Wakes the background thread of registries created with `with_lazy_ticks`
+    /// up when a timer is started.
+    wakeup: Arc<Wakeup>,
+    /// Whether the registry was created with `with_lazy_ticks`. Their current time
+    /// only moves when the background thread wakes up, so it can be many ticks behind.
+    lazy_ticks: bool,
     state: Mutex<State>,
 }
 
+/// Lets the background thread sleep until the next tick with timers.
+struct Wakeup {
+    notified: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Wakeup {
+    fn notify(&self) {
+        *self.notified.lock().unwrap() = true;
+        self.changed.notify_one();
+    }
+
+    /// Blocks until `notify` is called or `timeout` passes. `None` waits until `notify` is called.
+    fn wait(&self, timeout: Option<Duration>) {
+        let notified = self.notified.lock().unwrap();
+
+        let mut notified = match timeout {
+            None => self
+                .changed
+                .wait_while(notified, |notified| !*notified)
+                .unwrap(),
+            Some(timeout) => {
+                self.changed
+                    .wait_timeout_while(notified, timeout, |notified| !*notified)
+                    .unwrap()
+                    .0
+            }
+        };
+
+        *notified = false;
+    }
+}
+
 pub struct State {
     next_timer_id: usize,
+    /// Incremented every time `next_timer_id` wraps around so
+    /// timers that reuse an id can be told apart.
+    generation: u64,
+    /// The number of ticks since the registry was created.
+    /// The bucket being expired is the one the current time is placed in.
     current_time: u64,
     buckets: Vec<DoublyLinkedList<Timer>>,
+    /// Returns the position of the bucket the timers that expire on a tick go to.
+    placement: Box<Placement>,
+    /// The number of pending timers that expire on each tick that has any.
+    due: BTreeMap<u64, usize>,
+    /// The node of every pending timer by id and generation, so timers can be
+    /// stopped without searching their bucket. Timers are removed when they
+    /// expire or are stopped.
+    nodes: HashMap<(usize, u64), TimerNode>,
+    /// Used by every randomized feature, so registries created with the same seed
+    /// make the same choices.
+    rng: XorShift,
 }
 
-const NUM_BUCKETS: usize = 256;
+/// A xorshift64 generator. Not suitable for anything that needs unpredictability.
+struct XorShift(u64);
 
-fn lowest_8_bits(n: u32) -> u32 {
-    n & 0xFF
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero or every number after it is zero too.
+        Self(if seed == 0 {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            seed
+        })
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
 }
 
-fn highest_24_bits(n: u32) -> u32 {
-    n & 0xFFFFFF00
+/// A node in one of the buckets.
+struct TimerNode(*mut Node<Timer>);
+
+// Nodes are only used while the state is locked.
+unsafe impl Send for TimerNode {}
+
+impl State {
+    fn bucket_position(&self, tick: u64) -> usize {
+        (self.placement)(tick, self.buckets.len() as u32) % self.buckets.len()
+    }
+}
+
+type Placement = dyn Fn(u64, u32) -> usize + Send + Sync;
+
+const NUM_BUCKETS: usize = 256;
+
+fn lowest_8_bits(n: u64) -> u64 {
+    n & 0xFF
 }
 
 impl Registry {
     pub fn new() -> Arc<Self> {
+        let registry = Arc::new(Self::without_bookkeeping());
+        let registry_clone = Arc::downgrade(&registry);
+        std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
+        registry
+    }
+
+    /// Creates a registry that calls `placement` with a tick and the number of buckets
+    /// to choose the bucket the timers that expire on that tick go to, instead of
+    /// using the lowest 8 bits of the tick.
+    ///
+    /// Timers for ticks that are a multiple of the number of buckets apart always share
+    /// a bucket with the default placement, which makes some buckets a lot longer than
+    /// others when most timers are started with a few delays. A placement that hashes
+    /// the tick, e.g. multiply-shift, spreads them across the buckets.
+    ///
+    /// Positions are taken modulo the number of buckets.
+    pub fn with_placement(
+        placement: impl Fn(u64, u32) -> usize + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        let registry = Self::without_bookkeeping();
+        lock_clearing_poison(&registry.state).placement = Box::new(placement);
+
+        let registry = Arc::new(registry);
+        let registry_clone = Arc::downgrade(&registry);
+        std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
+        registry
+    }
+
+    /// Creates a registry whose randomized features, e.g. `start_timer_with_jitter`,
+    /// make the same choices as every other registry created with `seed`.
+    ///
+    /// Registries created any other way are seeded with the current time.
+    pub fn with_seed(seed: u64) -> Arc<Self> {
+        let registry = Self::without_bookkeeping();
+        lock_clearing_poison(&registry.state).rng = XorShift::new(seed);
+
+        let registry = Arc::new(registry);
+        let registry_clone = Arc::downgrade(&registry);
+        std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
+        registry
+    }
+
+    /// Creates a registry whose background thread sleeps until the next tick
+    /// a timer expires on instead of waking up every tick, skipping the empty buckets.
+    pub fn with_lazy_ticks() -> Arc<Self> {
+        let mut registry = Self::without_bookkeeping();
+        registry.lazy_ticks = true;
+
+        let registry = Arc::new(registry);
+        let registry_clone = Arc::downgrade(&registry);
+        std::thread::spawn(move || lazy_bookkeeping(registry_clone));
+        registry
+    }
+
+    /// Creates a registry that is not ticked by a background thread.
+    /// Timers only expire when `expire_timers` is called.
+    pub fn without_bookkeeping() -> Self {
         let mut buckets = Vec::new();
         buckets.resize_with(NUM_BUCKETS, DoublyLinkedList::new);
 
-        let registry = Arc::new(Self {
+        Self {
+            started_at: Instant::now(),
+            wakeup: Arc::new(Wakeup {
+                notified: Mutex::new(false),
+                changed: Condvar::new(),
+            }),
+            lazy_ticks: false,
             state: Mutex::new(State {
                 next_timer_id: 0,
+                generation: 0,
                 current_time: 0,
                 buckets,
+                placement: Box::new(|tick, _| lowest_8_bits(tick) as usize),
+                due: BTreeMap::new(),
+                nodes: HashMap::new(),
+                rng: XorShift::new(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_nanos() as u64,
+                ),
             }),
-        });
-        let registry_clone = Arc::downgrade(&registry);
-        std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
-        registry
+        }
     }
 
+    /// Starts a timer that expires after `expires_in`.
+    ///
+    /// The timer fires on the first tick at or after `expires_in`, `ceil(expires_in / tick)`
+    /// ticks from now: never before `expires_in` has passed and never a whole tick after.
+    /// Timers that expire in less than a tick fire on the next tick.
+    ///
+    /// Timers that expire on the same tick run in the order they expire in,
+    /// counting fractions of a tick, and then in the order they were started.
+    ///
+    /// `expires_in` can be any number of revolutions of the wheel away: timers wait
+    /// in their bucket, behind the ones that expire sooner, until the wheel reaches their tick.
     pub fn start_timer(
         &self,
         expires_in: Duration,
         expire_action: impl FnOnce() + Send + Sync + 'static,
     ) -> TimerHandle {
-        let mut state = self.state.lock().unwrap();
+        let mut state = lock_clearing_poison(&self.state);
+        let expires_at = self.now(&state) + expires_in;
+        let handle = insert_timer(&mut state, expires_at, Box::new(expire_action));
+        self.wakeup.notify();
+        handle
+    }
 
-        let timer_id = state.next_timer_id;
-        state.next_timer_id = state.next_timer_id.saturating_add(1);
+    /// Starts a timer that expires after `expires_in` plus a random delay of up to `jitter`,
+    /// so timers started together for the same delay don't all expire on the same tick.
+    pub fn start_timer_with_jitter(
+        &self,
+        expires_in: Duration,
+        jitter: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> TimerHandle {
+        let mut state = lock_clearing_poison(&self.state);
+        let jitter_nanos = state.rng.next_u64() as u128 % (jitter.as_nanos() + 1);
+        let expires_at = self.now(&state) + expires_in + Duration::from_nanos(jitter_nanos as u64);
+        let handle = insert_timer(&mut state, expires_at, Box::new(expire_action));
+        self.wakeup.notify();
+        handle
+    }
 
-        let expires_in_as_seconds = expires_in.as_secs() as u32;
+    /// Starts a timer that expires when the wheel reaches `tick`,
+    /// counting from the tick the registry was created at.
+    ///
+    /// Timers for ticks that have already been reached expire on the next tick.
+    pub fn start_timer_at_tick(
+        &self,
+        tick: u64,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> TimerHandle {
+        let mut state = lock_clearing_poison(&self.state);
+        let expires_at = Duration::from_secs(tick).max(self.now(&state));
+        let handle = insert_timer(&mut state, expires_at, Box::new(expire_action));
+        self.wakeup.notify();
+        handle
+    }
 
-        let highest_24_bits = highest_24_bits(expires_in_as_seconds);
-        let lowest_8_bits = lowest_8_bits(expires_in_as_seconds);
+    /// Returns the time new timers count their expiration from.
+    ///
+    /// The background thread of lazy registries may have been asleep for many ticks,
+    /// so their timers count from the time that passed since the wheel was at tick 0
+    /// instead of from the current tick, or they would fire as soon as it wakes up.
+    fn now(&self, state: &State) -> Duration {
+        let current_time = Duration::from_secs(state.current_time);
 
-        // TODO: if the number of seconds that the time should wait before expiring
-        // is greater than the number of buckets, the timer should go to a overflow list.
-        let bucket_position =
-            (state.current_time + lowest_8_bits as u64) as usize % state.buckets.len();
+        if self.lazy_ticks {
+            self.started_at.elapsed().max(current_time)
+        } else {
+            current_time
+        }
+    }
 
-        let bucket = &mut state.buckets[bucket_position];
+    pub fn stop_timer(&self, timer_handle: &TimerHandle) {
+        let mut state = lock_clearing_poison(&self.state);
+        let state = &mut *state;
 
-        insert_node_in_list(
-            bucket,
-            Timer {
-                id: timer_id,
-                highest_24_bits,
-                expire_action: Some(Box::new(expire_action)),
-            },
-        );
+        let key = (timer_handle.timer_id, timer_handle.generation);
 
-        TimerHandle {
-            bucket_position,
-            timer_id,
+        if let Some(TimerNode(node)) = state.nodes.remove(&key) {
+            let tick = unsafe { expiration_tick((*node).value.as_ref().unwrap().expires_at) };
+            state.buckets[timer_handle.bucket_position].remove(node);
+            remove_due(&mut state.due, tick, 1);
         }
     }
 
-    pub fn stop_timer(&self, timer_handle: &TimerHandle) {
-        let mut state = self.state.lock().unwrap();
+    /// Returns the id of every pending timer and the number of ticks left until it expires,
+    /// bucket by bucket, to see how the timers are spread across the buckets.
+    pub fn iter_pending(&self) -> Vec<(usize, u64)> {
+        let mut state = lock_clearing_poison(&self.state);
+        let current_time = state.current_time;
+
+        state
+            .buckets
+            .iter_mut()
+            .flat_map(|bucket| bucket.iter_mut())
+            .map(|node| unsafe {
+                let timer = (*node).value.as_ref().unwrap();
+                (timer.id, expiration_tick(timer.expires_at) - current_time)
+            })
+            .collect()
+    }
 
-        let bucket = &mut state.buckets[timer_handle.bucket_position];
+    /// Returns the id of every timer in bucket `i`, in the order they are in the bucket,
+    /// with the tick it expires on without its lowest 8 bits: the turn of the wheel
+    /// it expires in with the default placement.
+    ///
+    /// Timers are kept sorted by when they expire, so the turns never go down.
+    ///
+    /// Panics if `i` is not the position of a bucket.
+    pub fn timers_in_bucket(&self, i: usize) -> Vec<(usize, u64)> {
+        let mut state = lock_clearing_poison(&self.state);
+
+        state.buckets[i]
+            .iter_mut()
+            .map(|node| unsafe {
+                let timer = (*node).value.as_ref().unwrap();
+                (timer.id, expiration_tick(timer.expires_at) >> 8)
+            })
+            .collect()
+    }
 
-        let mut node_to_remove = None;
+    /// Returns the next tick a timer expires on, or `None` if there are no timers.
+    pub fn next_expiration(&self) -> Option<u64> {
+        let state = lock_clearing_poison(&self.state);
+        state.due.keys().next().copied()
+    }
 
-        for node in bucket.iter_mut() {
-            unsafe {
-                if (*node).value.as_ref().unwrap().id == timer_handle.timer_id {
-                    node_to_remove = Some(node);
-                    break;
-                }
-            }
-        }
+    /// Returns the number of ticks until the next timer expires, or `None` if there are no timers,
+    /// so whatever drives the wheel can sleep until then.
+    pub fn ticks_until_next(&self) -> Option<u64> {
+        let state = lock_clearing_poison(&self.state);
+        let next_expiration = state.due.keys().next()?;
+        Some(next_expiration.saturating_sub(state.current_time))
+    }
 
-        if let Some(node) = node_to_remove {
-            bucket.remove(node);
-        }
+    /// Advances the wheel to `tick`, going straight to the ticks timers expire on
+    /// instead of visiting every bucket on the way.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn advance_to(&self, tick: u64) -> usize {
+        advance_to(&mut lock_clearing_poison(&self.state), tick)
     }
 
-    pub fn expire_timers(&self) {
-        let mut state = self.state.lock().unwrap();
+    /// Advances the wheel by `ticks` ticks at once and runs the actions of every timer
+    /// that expires on the way, in the order they expire, e.g. to catch up after a pause.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn expire_timers_by(&self, ticks: u64) -> usize {
+        let mut state = lock_clearing_poison(&self.state);
+        let tick = state.current_time.saturating_add(ticks);
+        advance_to(&mut state, tick)
+    }
 
-        state.current_time = (state.current_time + 1) % state.buckets.len() as u64;
+    /// Advances the wheel by one tick and runs the actions of the timers that expired.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn expire_timers(&self) -> usize {
+        let mut state = lock_clearing_poison(&self.state);
 
-        let bucket_index = state.current_time as usize;
+        state.current_time += 1;
 
-        let current_time_highest_24_bits = highest_24_bits(state.current_time as u32);
+        expire_current_tick(&mut state)
+    }
+}
 
-        let bucket = &mut state.buckets[bucket_index];
+/// Locks `mutex`, clearing the poison left by an action that panicked while it was held.
+///
+/// Expired timers are removed before their actions run,
+/// so a panicking action doesn't leave the state half updated.
+fn lock_clearing_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
 
-        unsafe {
-            let mut current = bucket.head();
+impl Drop for Registry {
+    fn drop(&mut self) {
+        // Lets the background thread notice the registry is gone.
+        self.wakeup.notify();
+    }
+}
 
-            while current != bucket.dummy_tail {
-                let timer = (*current).value.as_mut().unwrap();
+/// Moves the current time to `tick`, going straight to the ticks timers expire on.
+///
+/// Returns the number of actions that ran.
+fn advance_to(state: &mut State, tick: u64) -> usize {
+    let mut num_expired = 0;
 
-                if timer.highest_24_bits != current_time_highest_24_bits {
-                    break;
-                }
+    while let Some(&due) = state.due.range(..=tick).next().map(|(due, _)| due) {
+        state.current_time = due;
+        num_expired += expire_current_tick(state);
+    }
 
-                let node = current;
-                current = (*current).next;
+    state.current_time = state.current_time.max(tick);
+
+    num_expired
+}
+
+/// Runs the actions of the timers that expire on the current tick.
+///
+/// Returns the number of actions that ran.
+fn expire_current_tick(state: &mut State) -> usize {
+    let current_time = state.current_time;
+    let bucket_index = state.bucket_position(current_time);
+
+    let bucket = &mut state.buckets[bucket_index];
+    let nodes = &mut state.nodes;
 
-                let f = (timer.expire_action.take()).unwrap();
+    let mut expired = Vec::new();
 
-                (f)();
+    // Every timer of the tick is removed before any action runs, so the registry
+    // is still consistent if an action panics. The timers that expired with it are lost
+    // instead of staying at the head of the bucket, where they would keep the later
+    // timers in it from ever being reached.
+    unsafe {
+        let mut current = bucket.head();
 
-                bucket.remove(node);
+        while current != bucket.dummy_tail {
+            let timer = (*current).value.as_mut().unwrap();
+
+            // Timers for later ticks can share the bucket.
+            if expiration_tick(timer.expires_at) != current_time {
+                break;
             }
+
+            let node = current;
+            current = (*current).next;
+
+            nodes.remove(&(timer.id, timer.generation));
+            expired.push((timer.expire_action.take()).unwrap());
+            bucket.remove(node);
         }
     }
+
+    remove_due(&mut state.due, current_time, expired.len());
+
+    let num_expired = expired.len();
+
+    for f in expired {
+        (f)();
+    }
+
+    num_expired
+}
+
+/// Returns the tick a timer that expires `expires_at` after the registry was created
+/// fires on: the first tick at or after `expires_at`.
+fn expiration_tick(expires_at: Duration) -> u64 {
+    expires_at.as_secs() + u64::from(expires_at.subsec_nanos() > 0)
+}
+
+/// Records that `count` timers that expire on `tick` are not pending anymore.
+fn remove_due(due: &mut BTreeMap<u64, usize>, tick: u64, count: usize) {
+    if count == 0 {
+        return;
+    }
+
+    let pending = due.get_mut(&tick).unwrap();
+    *pending -= count;
+    if *pending == 0 {
+        due.remove(&tick);
+    }
+}
+
+/// Adds a timer that expires `expires_at` after the registry was created.
+fn insert_timer(
+    state: &mut State,
+    expires_at: Duration,
+    expire_action: Box<ExpireAction>,
+) -> TimerHandle {
+    // The current time advances before a bucket is expired,
+    // so the earliest a timer can expire is the next tick.
+    let expires_at = expires_at.max(Duration::from_secs(state.current_time + 1));
+    let tick = expiration_tick(expires_at);
+
+    let timer_id = state.next_timer_id;
+    let generation = state.generation;
+    state.next_timer_id = state.next_timer_id.wrapping_add(1);
+    if state.next_timer_id == 0 {
+        state.generation += 1;
+    }
+
+    // The timer expires when the current time is placed in its bucket
+    // and matches its tick.
+    let bucket_position = state.bucket_position(tick);
+
+    *state.due.entry(tick).or_default() += 1;
+
+    let bucket = &mut state.buckets[bucket_position];
+
+    let node = insert_node_in_list(
+        bucket,
+        Timer {
+            id: timer_id,
+            generation,
+            expires_at,
+            expire_action: Some(expire_action),
+        },
+    );
+    state.nodes.insert((timer_id, generation), TimerNode(node));
+
+    TimerHandle {
+        bucket_position,
+        timer_id,
+        generation,
+    }
 }
 
-fn insert_node_in_list(list: &mut DoublyLinkedList<Timer>, timer: Timer) {
-    let node = find_node_to_insert_timer_after(list, timer.highest_24_bits);
-    list.insert_after(node, timer);
+fn insert_node_in_list(list: &mut DoublyLinkedList<Timer>, timer: Timer) -> *mut Node<Timer> {
+    let node = find_node_to_insert_timer_after(list, timer.expires_at);
+    list.insert_after(node, timer)
 }
 
+/// Returns the last node with a timer that expires at or before `expires_at`,
+/// so timers that expire at the same time stay in the order they were started.
 fn find_node_to_insert_timer_after(
     list: &mut DoublyLinkedList<Timer>,
-    highest_24_bits: u32,
+    expires_at: Duration,
 ) -> *mut Node<Timer> {
     if list.is_empty() {
         list.dummy_head
     } else {
         for node in list.iter_mut() {
             unsafe {
-                let node_highest_24_bits = (*node).value.as_ref().unwrap().highest_24_bits;
-                match node_highest_24_bits.cmp(&highest_24_bits) {
-                    std::cmp::Ordering::Less => { /* no-op */ }
-                    std::cmp::Ordering::Equal => return node,
-                    std::cmp::Ordering::Greater => return (*node).previous,
+                let node_expires_at = (*node).value.as_ref().unwrap().expires_at;
+                if node_expires_at > expires_at {
+                    return (*node).previous;
                 }
             }
         }
 
-        unreachable!()
+        // Every timer in the list expires before the new timer.
+        unsafe { (*list.dummy_tail).previous }
     }
 }
 
@@ -322,32 +704,72 @@ pub fn per_tick_bookkeeping(registry: Weak<Registry>) {
                 return;
             }
             Some(registry) => {
-                registry.expire_timers();
+                // A panicking action only takes the timers that expired with it down.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| registry.expire_timers()));
             }
         }
     }
 }
 
+/// Sleeps until the next tick a timer expires on, or until a timer is started,
+/// and advances the wheel to the tick the current time falls in.
+fn lazy_bookkeeping(registry: Weak<Registry>) {
+    let wakeup = match registry.upgrade() {
+        None => {
+            return;
+        }
+        Some(registry) => Arc::clone(&registry.wakeup),
+    };
+
+    loop {
+        let timeout = match registry.upgrade() {
+            None => {
+                return;
+            }
+            Some(registry) => {
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    registry.advance_to(registry.started_at.elapsed().as_secs())
+                }));
+
+                registry.next_expiration().map(|tick| {
+                    (registry.started_at + Duration::from_secs(tick))
+                        .saturating_duration_since(Instant::now())
+                })
+            }
+        };
+
+        wakeup.wait(timeout);
+    }
+}
+
 type ExpireAction = dyn FnOnce() + Send + Sync;
 
 pub struct Timer {
     id: usize,
-    highest_24_bits: u32,
+    generation: u64,
+    /// How long after the registry was created the timer expires.
+    expires_at: Duration,
     expire_action: Option<Box<ExpireAction>>,
 }
 
 /// Can be used to interact with a Timer after it has been registered.
 /// Could be used to cancel a timer for example.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct TimerHandle {
     /// The position of the bucket that the timer has been added to.
     bucket_position: usize,
     /// The timer identifier.
     timer_id: usize,
+    /// The generation the timer identifier belongs to.
+    generation: u64,
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::{Duration, Instant};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
 
     use super::*;
 
@@ -371,4 +793,537 @@ mod tests {
 
         std::thread::sleep(Duration::from_secs(5));
     }
+
+    fn bucket_ids(registry: &Registry, bucket_position: usize) -> Vec<(usize, u64)> {
+        let mut state = registry.state.lock().unwrap();
+        state.buckets[bucket_position]
+            .iter_mut()
+            .map(|node| unsafe {
+                let timer = (*node).value.as_ref().unwrap();
+                (timer.id, timer.generation)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stale_handle_does_not_stop_timer_that_reused_its_id() {
+        let registry = Registry::without_bookkeeping();
+
+        let stale_handle = registry.start_timer(Duration::from_secs(1), || {});
+        registry.expire_timers();
+
+        // Make the next timer id wrap around so it is reused.
+        registry.state.lock().unwrap().next_timer_id = usize::MAX;
+        let _ = registry.start_timer(Duration::from_secs(2), || {});
+        let handle = registry.start_timer(Duration::from_secs(256), || {});
+
+        assert_eq!(stale_handle.timer_id, handle.timer_id);
+        assert_eq!(stale_handle.bucket_position, handle.bucket_position);
+        assert_ne!(stale_handle.generation, handle.generation);
+
+        registry.stop_timer(&stale_handle);
+        assert_eq!(vec![(0, 1)], bucket_ids(&registry, handle.bucket_position));
+
+        registry.stop_timer(&handle);
+        assert!(bucket_ids(&registry, handle.bucket_position).is_empty());
+    }
+
+    #[test]
+    fn timer_straddling_the_end_of_the_wheel_fires_on_time() {
+        let registry = Registry::without_bookkeeping();
+
+        for _ in 0..250 {
+            registry.expire_timers();
+        }
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        // Both timers end up in bucket 4, one and two turns of the wheel later.
+        for (id, expires_in) in [(0, 10), (1, 266)] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired.lock().unwrap().push(id);
+            });
+        }
+
+        for _ in 0..9 {
+            registry.expire_timers();
+        }
+        assert!(fired.lock().unwrap().is_empty());
+
+        registry.expire_timers();
+        assert_eq!(vec![0], *fired.lock().unwrap());
+
+        for _ in 0..255 {
+            registry.expire_timers();
+        }
+        assert_eq!(vec![0], *fired.lock().unwrap());
+
+        registry.expire_timers();
+        assert_eq!(vec![0, 1], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn start_timer_at_tick_fires_at_that_tick() {
+        let registry = Registry::without_bookkeeping();
+
+        for _ in 0..10 {
+            registry.expire_timers();
+        }
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        // Same bucket, different turns of the wheel.
+        for tick in [300, 44] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer_at_tick(tick, move || {
+                fired.lock().unwrap().push(tick);
+            });
+        }
+
+        for _ in 10..43 {
+            registry.expire_timers();
+        }
+        assert!(fired.lock().unwrap().is_empty());
+
+        registry.expire_timers();
+        assert_eq!(vec![44], *fired.lock().unwrap());
+
+        for _ in 44..299 {
+            registry.expire_timers();
+        }
+        assert_eq!(vec![44], *fired.lock().unwrap());
+
+        registry.expire_timers();
+        assert_eq!(vec![44, 300], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn expire_timers_returns_the_number_of_actions_that_ran() {
+        let registry = Registry::without_bookkeeping();
+
+        for expires_in in [1, 1, 2, 257] {
+            registry.start_timer(Duration::from_secs(expires_in), || {});
+        }
+
+        assert_eq!(2, registry.expire_timers());
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(0, registry.expire_timers());
+    }
+
+    #[test]
+    fn timers_on_the_same_tick_fire_in_expiration_order() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for (id, expires_in) in [(0, 1700), (1, 1200), (2, 1700), (3, 2000)] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_millis(expires_in), move || {
+                fired.lock().unwrap().push(id);
+            });
+        }
+
+        registry.expire_timers();
+        assert!(fired.lock().unwrap().is_empty());
+
+        registry.expire_timers();
+        assert_eq!(vec![1, 0, 2, 3], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn timers_go_to_the_bucket_chosen_by_the_placement() {
+        // Multiply-shift.
+        let placement = |tick: u64, num_buckets: u32| {
+            (tick.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 56) as usize % num_buckets as usize
+        };
+
+        let registry = Registry::without_bookkeeping();
+        registry.state.lock().unwrap().placement = Box::new(placement);
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for tick in [3, 259, 515, 40, 7] {
+            let fired = Arc::clone(&fired);
+            let handle = registry.start_timer(Duration::from_secs(tick), move || {
+                fired.lock().unwrap().push(tick);
+            });
+            assert_eq!(placement(tick, 256), handle.bucket_position);
+        }
+
+        let mut expected = Vec::new();
+        for tick in 1..=515 {
+            registry.expire_timers();
+
+            if [3, 7, 40, 259, 515].contains(&tick) {
+                expected.push(tick);
+            }
+            assert_eq!(expected, *fired.lock().unwrap());
+        }
+    }
+
+    /// Counts the live values it is captured in.
+    struct Tracked(Arc<AtomicUsize>);
+
+    impl Tracked {
+        fn new(live: &Arc<AtomicUsize>) -> Self {
+            live.fetch_add(1, Ordering::SeqCst);
+            Self(Arc::clone(live))
+        }
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Also meant to be run with `cargo miri test`, which catches the leaks and
+    // use-after-frees that the count can't.
+    #[test]
+    fn stopped_and_expired_timers_are_freed() {
+        let live = Arc::new(AtomicUsize::new(0));
+
+        let registry = Registry::without_bookkeeping();
+
+        let handles: Vec<_> = (0..300)
+            .map(|i| {
+                let tracked = Tracked::new(&live);
+                // Same tick and different turns of the wheel, in and out of order.
+                let expires_in = Duration::from_millis((i * 7919) % 600_000);
+                registry.start_timer(expires_in, move || drop(tracked))
+            })
+            .collect();
+        assert_eq!(300, live.load(Ordering::SeqCst));
+
+        for handle in handles.iter().step_by(3) {
+            registry.stop_timer(handle);
+        }
+        assert_eq!(200, live.load(Ordering::SeqCst));
+
+        for _ in 0..300 {
+            registry.expire_timers();
+        }
+        let pending = live.load(Ordering::SeqCst);
+        assert!(0 < pending && pending < 200, "{pending}");
+
+        // Stopping a timer again frees nothing twice.
+        for handle in handles.iter().step_by(3) {
+            registry.stop_timer(handle);
+        }
+
+        // The timers that are still pending are freed with the registry.
+        drop(registry);
+
+        assert_eq!(0, live.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn advance_to_skips_the_ticks_without_timers() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for tick in [700, 5, 5000] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer_at_tick(tick, move || {
+                fired.lock().unwrap().push(tick);
+            });
+        }
+        let stopped = registry.start_timer_at_tick(6, || {});
+        registry.stop_timer(&stopped);
+
+        assert_eq!(Some(5), registry.next_expiration());
+
+        assert_eq!(2, registry.advance_to(1000));
+        assert_eq!(vec![5, 700], *fired.lock().unwrap());
+        assert_eq!(Some(5000), registry.next_expiration());
+
+        // A timer for the tick the wheel was advanced to expires on the next one.
+        registry.start_timer_at_tick(1000, || {});
+        assert_eq!(Some(1001), registry.next_expiration());
+        assert_eq!(1, registry.expire_timers());
+
+        assert_eq!(1, registry.advance_to(5000));
+        assert_eq!(vec![5, 700, 5000], *fired.lock().unwrap());
+        assert_eq!(None, registry.next_expiration());
+    }
+
+    #[test]
+    fn stopped_and_expired_timers_leave_the_node_map() {
+        let registry = Registry::without_bookkeeping();
+
+        let num_nodes = || registry.state.lock().unwrap().nodes.len();
+        let fired = Arc::new(AtomicUsize::new(0));
+
+        let mut started = 0;
+        while started < 1_000_000 {
+            let mut handles = Vec::new();
+            for i in 0..100 {
+                let fired = Arc::clone(&fired);
+                handles.push(
+                    registry.start_timer(Duration::from_secs(1 + i % 3), move || {
+                        fired.fetch_add(1, Ordering::Relaxed);
+                    }),
+                );
+            }
+            started += handles.len();
+
+            for handle in handles.iter().step_by(10) {
+                registry.stop_timer(handle);
+            }
+
+            registry.expire_timers();
+
+            // Timers are pending for at most 3 ticks.
+            assert!(num_nodes() <= 300, "{} nodes", num_nodes());
+        }
+
+        while registry.next_expiration().is_some() {
+            registry.expire_timers();
+        }
+
+        assert_eq!(0, num_nodes());
+        assert_eq!(900_000, fired.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn registries_with_the_same_seed_jitter_timers_the_same_way() {
+        let positions = |registry: &Registry| {
+            (0..100)
+                .map(|_| {
+                    registry
+                        .start_timer_with_jitter(
+                            Duration::from_secs(1),
+                            Duration::from_secs(200),
+                            || {},
+                        )
+                        .bucket_position
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let first = positions(&Registry::with_seed(42));
+        let second = positions(&Registry::with_seed(42));
+        let other_seed = positions(&Registry::with_seed(7));
+
+        assert_eq!(first, second);
+        assert_ne!(first, other_seed);
+        assert!(first.iter().all(|&position| (1..=201).contains(&position)));
+    }
+
+    #[test]
+    fn lazy_ticks_wake_up_for_new_timers() {
+        let registry = Registry::with_lazy_ticks();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        // The background thread is asleep with no timers to wait for.
+        std::thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        registry.start_timer(Duration::from_secs(1), move || {
+            let _ = sender.lock().unwrap().send(());
+        });
+
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn lazy_ticks_count_new_timers_from_the_current_time() {
+        let registry = Registry::with_lazy_ticks();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        // The background thread sleeps through more than a tick, so the wheel falls behind.
+        std::thread::sleep(Duration::from_millis(1500));
+
+        let start = Instant::now();
+        registry.start_timer(Duration::from_secs(1), move || {
+            let _ = sender.lock().unwrap().send(());
+        });
+
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn registry_keeps_working_after_an_action_panics() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        registry.start_timer(Duration::from_secs(1), || panic!("action panicked"));
+        // Expires on the same tick, after the panicking timer.
+        registry.start_timer(Duration::from_secs(1), || {});
+        for id in 0..2 {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(2), move || {
+                fired.lock().unwrap().push(id);
+            });
+        }
+        // Shares the bucket of the panicking timer, a turn of the wheel later.
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(NUM_BUCKETS as u64 + 1), move || {
+            fired_clone.lock().unwrap().push(2);
+        });
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| registry.expire_timers()));
+        assert!(result.is_err());
+        assert!(registry.state.is_poisoned());
+
+        // The timers of the tick are gone and the other timers still expire.
+        assert_eq!(Some(2), registry.next_expiration());
+        assert!(!registry.state.is_poisoned());
+        assert_eq!(Some(1), registry.ticks_until_next());
+        // Only the timer a turn later is left in the bucket.
+        assert_eq!(1, registry.timers_in_bucket(1).len());
+        assert_eq!(2, registry.expire_timers());
+        assert_eq!(vec![0, 1], *fired.lock().unwrap());
+
+        assert_eq!(1, registry.advance_to(NUM_BUCKETS as u64 + 1));
+        assert_eq!(vec![0, 1, 2], *fired.lock().unwrap());
+        assert_eq!(None, registry.next_expiration());
+        assert!(registry.state.lock().unwrap().nodes.is_empty());
+    }
+
+    #[test]
+    fn iter_pending_reports_the_ticks_left_for_every_timer() {
+        let registry = Registry::without_bookkeeping();
+
+        for expires_in in [1, 257, 5, 300] {
+            registry.start_timer(Duration::from_secs(expires_in), || {});
+        }
+
+        assert_eq!(
+            vec![(0, 1), (1, 257), (2, 5), (3, 300)],
+            registry.iter_pending()
+        );
+
+        registry.expire_timers();
+
+        // Timer 1 shares the bucket of the timer that expired.
+        assert_eq!(vec![(1, 256), (2, 4), (3, 299)], registry.iter_pending());
+    }
+
+    #[test]
+    fn timers_fire_on_the_first_tick_at_or_after_their_delay() {
+        let registry = Registry::without_bookkeeping();
+
+        let turn = NUM_BUCKETS as u64 * 1000;
+        let mut delays: Vec<u64> = (0..20_000).step_by(250).collect();
+        for boundary in [turn, 2 * turn, 3 * turn] {
+            delays.extend([
+                boundary - 1000,
+                boundary - 1,
+                boundary,
+                boundary + 1,
+                boundary + 1000,
+            ]);
+        }
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for (id, &delay) in delays.iter().enumerate() {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_millis(delay), move || {
+                fired.lock().unwrap().push(id);
+            });
+        }
+
+        let mut fired_at = vec![None; delays.len()];
+        for tick in 1..=3 * NUM_BUCKETS as u64 + 2 {
+            registry.expire_timers();
+            for id in fired.lock().unwrap().drain(..) {
+                assert_eq!(None, fired_at[id], "delay {}ms fired twice", delays[id]);
+                fired_at[id] = Some(tick);
+            }
+        }
+
+        for (id, &delay) in delays.iter().enumerate() {
+            let expected = delay.div_ceil(1000).max(1);
+            assert_eq!(Some(expected), fired_at[id], "delay {delay}ms");
+        }
+    }
+
+    #[test]
+    fn timers_beyond_the_horizon_fire_on_their_own_tick() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for expires_in in [300, 44, 300 + NUM_BUCKETS as u64] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired.lock().unwrap().push(expires_in);
+            });
+        }
+
+        // The three timers share a bucket, the wheel reaches it on ticks 44, 300 and 556.
+        let mut fired_at = Vec::new();
+        for tick in 1..=600 {
+            registry.expire_timers();
+            for expires_in in fired.lock().unwrap().drain(..) {
+                fired_at.push((expires_in, tick));
+            }
+        }
+
+        assert_eq!(vec![(44, 44), (300, 300), (556, 556)], fired_at);
+    }
+
+    #[test]
+    fn ticks_until_next_counts_from_the_current_tick() {
+        let registry = Registry::without_bookkeeping();
+        assert_eq!(None, registry.ticks_until_next());
+
+        registry.start_timer(Duration::from_secs(300), || {});
+        let soonest = registry.start_timer(Duration::from_secs(7), || {});
+        assert_eq!(Some(7), registry.ticks_until_next());
+
+        registry.expire_timers();
+        registry.expire_timers();
+        assert_eq!(Some(5), registry.ticks_until_next());
+
+        registry.stop_timer(&soonest);
+        assert_eq!(Some(298), registry.ticks_until_next());
+    }
+
+    #[test]
+    fn timers_in_bucket_are_sorted_by_turn() {
+        let registry = Registry::without_bookkeeping();
+
+        // Every delay is 5 ticks past a multiple of the number of buckets.
+        for turn in [3, 0, 7, 1, 3] {
+            registry.start_timer(Duration::from_secs(turn * NUM_BUCKETS as u64 + 5), || {});
+        }
+
+        assert_eq!(
+            vec![(1, 0), (3, 1), (0, 3), (4, 3), (2, 7)],
+            registry.timers_in_bucket(5)
+        );
+        assert!(registry.timers_in_bucket(6).is_empty());
+    }
+
+    #[test]
+    fn expire_timers_by_catches_up_across_turns_of_the_wheel() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        // 44 and 300 share a bucket, and so do 45 and 301.
+        for expires_in in [300, 44, 1, 301, 256, 45, 257] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired.lock().unwrap().push(expires_in);
+            });
+        }
+
+        assert_eq!(6, registry.expire_timers_by(300));
+        assert_eq!(vec![1, 44, 45, 256, 257, 300], *fired.lock().unwrap());
+        assert_eq!(Some(1), registry.ticks_until_next());
+
+        assert_eq!(0, registry.expire_timers_by(0));
+        assert_eq!(1, registry.expire_timers_by(1));
+        assert_eq!(None, registry.next_expiration());
+    }
 }
@@ -0,0 +1,332 @@
+//! A hierarchy of four wheels with 256 slots each, as described by Varghese and Lauck.
+//!
+//! Each level is indexed by one byte of the tick a timer expires at, so together they
+//! cover every tick a u32 can hold instead of a single day.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(feature = "background-thread")]
+use std::{panic::AssertUnwindSafe, sync::Weak};
+
+const NUM_LEVELS: usize = 4;
+const SLOTS_PER_LEVEL: usize = 256;
+const BITS_PER_LEVEL: u32 = 8;
+
+type ExpireAction = dyn FnOnce() + Send + Sync;
+
+pub struct Registry {
+    /// How much time a tick covers.
+    resolution: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    next_timer_id: u64,
+    /// The current tick. Wraps around after `u32::MAX`.
+    current_tick: u32,
+    levels: [Vec<Vec<Timer>>; NUM_LEVELS],
+    /// The level and slot every pending timer is in.
+    locations: HashMap<u64, (usize, usize)>,
+}
+
+struct Timer {
+    id: u64,
+    expires_at: u32,
+    expire_action: Box<ExpireAction>,
+}
+
+/// Points to a timer started with `Registry::start_timer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    id: u64,
+}
+
+/// The reason a timer could not be started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Base256Error {
+    /// Timers have to expire less than `u32::MAX` ticks in the future.
+    OutOfRange,
+}
+
+impl std::fmt::Display for Base256Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Base256Error::OutOfRange => write!(f, "timer expires more than u32::MAX ticks away"),
+        }
+    }
+}
+
+impl std::error::Error for Base256Error {}
+
+impl State {
+    /// Returns the level and slot of a timer that expires at `expires_at`:
+    /// the level of the most significant byte that differs from the current tick,
+    /// and that byte of `expires_at`.
+    fn placement(&self, expires_at: u32) -> (usize, usize) {
+        let differing_bits = u32::BITS - (expires_at ^ self.current_tick).leading_zeros();
+        let level = (differing_bits.saturating_sub(1) / BITS_PER_LEVEL) as usize;
+        let slot = (expires_at >> (level as u32 * BITS_PER_LEVEL)) as usize % SLOTS_PER_LEVEL;
+        (level, slot)
+    }
+
+    fn place(&mut self, timer: Timer) {
+        let (level, slot) = self.placement(timer.expires_at);
+        self.locations.insert(timer.id, (level, slot));
+        self.levels[level][slot].push(timer);
+    }
+
+    /// Advances the current tick and returns the timers that expire on it.
+    fn advance(&mut self) -> Vec<Timer> {
+        self.current_tick = self.current_tick.wrapping_add(1);
+
+        // A level's slot is reached when every level below it wraps around.
+        // Higher levels go first so their timers can go all the way down in one tick.
+        let mut expired = Vec::new();
+        for level in (0..NUM_LEVELS).rev() {
+            let shift = level as u32 * BITS_PER_LEVEL;
+            if self.current_tick & ((1 << shift) - 1) != 0 {
+                continue;
+            }
+
+            let slot = (self.current_tick >> shift) as usize % SLOTS_PER_LEVEL;
+            for timer in std::mem::take(&mut self.levels[level][slot]) {
+                if timer.expires_at == self.current_tick {
+                    self.locations.remove(&timer.id);
+                    expired.push(timer);
+                } else {
+                    self.place(timer);
+                }
+            }
+        }
+
+        expired
+    }
+}
+
+impl Registry {
+    /// Creates a registry whose ticks last a second.
+    pub fn new() -> Arc<Self> {
+        Self::with_resolution(Duration::from_secs(1))
+    }
+
+    /// Creates a registry whose ticks last `resolution`.
+    /// It is ticked by a background thread unless the `background-thread` feature is disabled.
+    pub fn with_resolution(resolution: Duration) -> Arc<Self> {
+        let registry = Arc::new(Self::without_bookkeeping(resolution));
+
+        #[cfg(feature = "background-thread")]
+        {
+            let registry_clone = Arc::downgrade(&registry);
+            std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
+        }
+
+        registry
+    }
+
+    /// Creates a registry that is not ticked by a background thread.
+    /// Timers only expire when `expire_timers` is called.
+    pub fn without_bookkeeping(resolution: Duration) -> Self {
+        assert!(!resolution.is_zero(), "ticks have to last some time");
+
+        Self {
+            resolution,
+            state: Mutex::new(State {
+                next_timer_id: 0,
+                current_tick: 0,
+                levels: std::array::from_fn(|_| {
+                    let mut slots = Vec::new();
+                    slots.resize_with(SLOTS_PER_LEVEL, Vec::new);
+                    slots
+                }),
+                locations: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Starts a timer that expires after `expires_in`, rounded down to a whole number of ticks.
+    /// Timers that expire in less than a tick expire on the next tick.
+    pub fn start_timer(
+        &self,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Result<TimerHandle, Base256Error> {
+        let ticks = (expires_in.as_nanos() / self.resolution.as_nanos()).max(1);
+        let ticks = u32::try_from(ticks)
+            .ok()
+            .filter(|&ticks| ticks < u32::MAX)
+            .ok_or(Base256Error::OutOfRange)?;
+
+        let mut state = self.state.lock().unwrap();
+
+        let id = state.next_timer_id;
+        state.next_timer_id += 1;
+
+        let expires_at = state.current_tick.wrapping_add(ticks);
+        state.place(Timer {
+            id,
+            expires_at,
+            expire_action: Box::new(expire_action),
+        });
+
+        Ok(TimerHandle { id })
+    }
+
+    /// Stops the timer `timer_handle` points to.
+    ///
+    /// Does nothing if the timer has already expired or been stopped.
+    pub fn stop_timer(&self, timer_handle: &TimerHandle) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some((level, slot)) = state.locations.remove(&timer_handle.id) {
+            state.levels[level][slot].retain(|timer| timer.id != timer_handle.id);
+        }
+    }
+
+    /// Advances the wheel by one tick and runs the actions of the timers that expired.
+    ///
+    /// Actions run without holding the lock, so they can start and stop timers.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn expire_timers(&self) -> usize {
+        let expired = self.state.lock().unwrap().advance();
+        let num_expired = expired.len();
+
+        for timer in expired {
+            (timer.expire_action)();
+        }
+
+        num_expired
+    }
+}
+
+#[cfg(feature = "background-thread")]
+fn per_tick_bookkeeping(registry: Weak<Registry>) {
+    loop {
+        let resolution = match registry.upgrade() {
+            None => {
+                return;
+            }
+            Some(registry) => registry.resolution,
+        };
+
+        std::thread::sleep(resolution);
+
+        match registry.upgrade() {
+            None => {
+                return;
+            }
+            Some(registry) => {
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| registry.expire_timers()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(registry: &Registry, timer_handle: &TimerHandle) -> usize {
+        registry.state.lock().unwrap().locations[&timer_handle.id].0
+    }
+
+    /// Starts a timer for every delay in ticks and returns the tick each one fired at,
+    /// after checking the level it started in.
+    fn fired_at(registry: &Registry, delays: &[(u32, usize)], ticks: u32) -> Vec<(u32, u32)> {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let start = registry.state.lock().unwrap().current_tick;
+
+        for &(delay, expected_level) in delays {
+            let fired = Arc::clone(&fired);
+            let handle = registry
+                .start_timer(Duration::from_secs(delay as u64), move || {
+                    fired.lock().unwrap().push(delay);
+                })
+                .unwrap();
+            assert_eq!(expected_level, level(registry, &handle), "delay {delay}");
+        }
+
+        let mut fired_at = Vec::new();
+        for _ in 0..ticks {
+            registry.expire_timers();
+            let tick = registry.state.lock().unwrap().current_tick;
+            for delay in fired.lock().unwrap().drain(..) {
+                fired_at.push((delay, tick.wrapping_sub(start)));
+            }
+        }
+        fired_at
+    }
+
+    #[test]
+    fn timers_cascade_from_the_first_three_levels() {
+        let registry = Registry::without_bookkeeping(Duration::from_secs(1));
+
+        let delays = [
+            (1, 0),
+            (255, 0),
+            (256, 1),
+            (257, 1),
+            (65_535, 1),
+            (65_536, 2),
+            (70_000, 2),
+        ];
+
+        assert_eq!(
+            delays.map(|(delay, _)| (delay, delay)).to_vec(),
+            fired_at(&registry, &delays, 70_000)
+        );
+        assert!(registry.state.lock().unwrap().locations.is_empty());
+
+        let handle = registry
+            .start_timer(Duration::from_secs(0x0100_0000), || {})
+            .unwrap();
+        assert_eq!(3, level(&registry, &handle));
+    }
+
+    #[test]
+    fn timers_cascade_from_the_highest_level() {
+        let registry = Registry::without_bookkeeping(Duration::from_secs(1));
+        // Skip to just before the third byte of the tick carries into the fourth.
+        registry.state.lock().unwrap().current_tick = 0x00FF_FFF0;
+
+        assert_eq!(
+            vec![(0x05, 0x05), (0x10, 0x10), (0x20, 0x20)],
+            fired_at(&registry, &[(0x05, 0), (0x10, 3), (0x20, 3)], 0x20)
+        );
+    }
+
+    #[test]
+    fn timers_fire_across_the_end_of_the_range() {
+        let registry = Registry::without_bookkeeping(Duration::from_secs(1));
+        registry.state.lock().unwrap().current_tick = u32::MAX - 5;
+
+        assert_eq!(
+            vec![(5, 5), (10, 10)],
+            fired_at(&registry, &[(5, 0), (10, 3)], 10)
+        );
+    }
+
+    #[test]
+    fn stopped_timers_do_not_fire_and_long_timers_are_rejected() {
+        let registry = Registry::without_bookkeeping(Duration::from_millis(10));
+
+        let handle = registry
+            .start_timer(Duration::from_secs(10), || panic!("stopped"))
+            .unwrap();
+        assert_eq!(1, level(&registry, &handle));
+        registry.stop_timer(&handle);
+
+        for _ in 0..1000 {
+            assert_eq!(0, registry.expire_timers());
+        }
+
+        assert_eq!(
+            Err(Base256Error::OutOfRange),
+            registry.start_timer(Duration::from_millis(10) * u32::MAX, || {})
+        );
+    }
+}
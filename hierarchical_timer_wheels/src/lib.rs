@@ -2,14 +2,50 @@
 #![feature(drain_filter)]
 
 use std::{
-    sync::{Arc, Mutex, Weak},
-    time::Duration,
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
 };
 
+#[cfg(feature = "background-thread")]
+use std::{panic::AssertUnwindSafe, sync::Weak};
+
+pub mod base256;
+pub mod manual;
+
 const SECONDS_IN_A_MINUTE: u32 = 60;
 const MINUTES_IN_A_HOUR: u32 = 60;
 const HOURS_IN_A_DAY: u32 = 24;
 
+/// The number of levels in the hierarchy.
+const NUM_LEVELS: u8 = 3;
+
+/// The id of the next registry that is built.
+static NEXT_REGISTRY_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Splits `secs` into the second, minute and hour of the day it falls on,
+/// which are the slots a timer that far out goes to with the default level sizes.
+pub const fn time_components(secs: u32) -> (u32, u32, u32) {
+    let (seconds, minutes, hours, _) = time_components_with_days(secs);
+    (seconds, minutes, hours)
+}
+
+/// Like `time_components`, but also returns the number of whole days in `secs`.
+pub const fn time_components_with_days(secs: u32) -> (u32, u32, u32, u32) {
+    let seconds_in_an_hour = SECONDS_IN_A_MINUTE * MINUTES_IN_A_HOUR;
+    let seconds_in_a_day = seconds_in_an_hour * HOURS_IN_A_DAY;
+
+    (
+        secs % SECONDS_IN_A_MINUTE,
+        secs / SECONDS_IN_A_MINUTE % MINUTES_IN_A_HOUR,
+        secs / seconds_in_an_hour % HOURS_IN_A_DAY,
+        secs / seconds_in_a_day,
+    )
+}
+
 struct DoublyLinkedList<T> {
     dummy_head: *mut Node<T>,
     dummy_tail: *mut Node<T>,
@@ -83,30 +119,40 @@ impl<T> DoublyLinkedList<T> {
     }
 
     fn remove(&mut self, node: *mut Node<T>) -> Box<Node<T>> {
+        self.unlink(node);
+        unsafe { Box::from_raw(node) }
+    }
+
+    /// Removes `node` from the list without freeing it.
+    fn unlink(&mut self, node: *mut Node<T>) {
         unsafe {
             let previous = (*node).previous;
             let next = (*node).next;
             (*previous).next = next;
             (*next).previous = previous;
-            Box::from_raw(node)
         }
     }
 
     fn push_back(&mut self, value: T) -> *mut Node<T> {
-        unsafe {
-            let node = Box::into_raw(Box::new(Node {
-                value: Some(value),
-                previous: std::ptr::null_mut(),
-                next: std::ptr::null_mut(),
-            }));
+        let node = Box::into_raw(Box::new(Node {
+            value: Some(value),
+            previous: std::ptr::null_mut(),
+            next: std::ptr::null_mut(),
+        }));
+
+        self.push_back_node(node);
+
+        node
+    }
 
+    /// Adds a node that is not in any list to the end of the list.
+    fn push_back_node(&mut self, node: *mut Node<T>) {
+        unsafe {
             let previous = (*self.dummy_tail).previous;
             (*node).previous = previous;
             (*previous).next = node;
             (*self.dummy_tail).previous = node;
             (*node).next = self.dummy_tail;
-
-            node
         }
     }
 
@@ -172,15 +218,115 @@ impl<T> Node<T> {
 }
 
 pub struct Registry {
-    state: Mutex<State>,
+    /// Stamped on the handles of the registry's timers, since every registry
+    /// gives out the same timer ids.
+    id: u64,
+    next_timer_id: AtomicU64,
+    /// The ids of the timers that have not expired or been stopped yet.
+    /// Handles to any other timer point to nodes that may have been freed.
+    pending: Mutex<HashSet<u64>>,
+    /// Read locked while timers are started or stopped and write locked while the clocks
+    /// advance, so timers are never added to or removed from a bucket that is being expired.
+    clocks: RwLock<Clocks>,
+    /// Every bucket has its own lock so timers can be started in different buckets at the same time.
+    buckets: Buckets,
+    /// While paused, ticks do not advance the clocks and timers do not expire.
+    paused: AtomicBool,
+    /// The number of ticks that were skipped while the registry was paused.
+    ticks_missed_while_paused: AtomicU64,
+    /// Called every time a timer moves to a lower level of the hierarchy.
+    on_cascade: Option<Arc<OnCascade>>,
 }
 
-pub struct State {
-    clocks: Clocks,
-    buckets: Buckets,
+type OnCascade = dyn Fn(CascadeEvent) + Send + Sync;
+
+/// A level of the hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+/// The reason a `Builder` could not build a registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The level has no buckets to put timers in.
+    EmptyLevel(Level),
+    /// The levels cover more seconds than fit in a u32.
+    HorizonTooLarge,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::EmptyLevel(level) => write!(f, "{level:?} level has no buckets"),
+            ConfigError::HorizonTooLarge => write!(f, "levels cover more than u32::MAX seconds"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Describes a timer moving from one level of the hierarchy to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CascadeEvent {
+    pub timer_id: u64,
+    /// The level the timer was in.
+    pub from: Level,
+    /// The level the timer was moved to.
+    pub to: Level,
+}
+
+/// What to do with the ticks that were missed while the registry was paused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumePolicy {
+    /// Run the missed ticks when resuming, expiring the timers that would have
+    /// expired if the registry had not been paused.
+    CatchUp,
+    /// Continue as if no time passed while the registry was paused.
+    Continue,
+}
+
+/// The number of buckets in each level of the hierarchy.
+#[derive(Debug, Clone, Copy)]
+struct LevelSizes {
+    /// The number of seconds in a minute.
+    seconds: u32,
+    /// The number of minutes in an hour.
+    minutes: u32,
+    /// The number of hours in a day.
+    hours: u32,
+}
+
+impl LevelSizes {
+    /// Returns the number of seconds it takes the clocks to wrap around.
+    fn horizon(&self) -> u32 {
+        self.seconds * self.minutes * self.hours
+    }
+
+    /// Returns how much time each slot in `level` covers.
+    fn resolution(&self, level: Level) -> Duration {
+        match level {
+            Level::Seconds => Duration::from_secs(1),
+            Level::Minutes => Duration::from_secs(self.seconds as u64),
+            Level::Hours => Duration::from_secs((self.minutes * self.seconds) as u64),
+        }
+    }
+
+    /// Splits `secs` into the second, minute and hour it falls on.
+    /// Hours are not wrapped around, see `time_components` for the default level sizes.
+    const fn time_components(&self, secs: u64) -> (u32, u32, u32) {
+        let seconds_in_an_hour = (self.minutes * self.seconds) as u64;
+        let hours = secs / seconds_in_an_hour;
+        let minutes = (secs % seconds_in_an_hour) / self.seconds as u64;
+        let seconds = secs % self.seconds as u64;
+        (seconds as u32, minutes as u32, hours as u32)
+    }
 }
 
 struct Clocks {
+    sizes: LevelSizes,
     /// The current second.
     second: u32,
     /// The current minute.
@@ -190,195 +336,704 @@ struct Clocks {
 }
 
 impl Clocks {
-    fn new() -> Self {
+    fn new(sizes: LevelSizes) -> Self {
         Self {
+            sizes,
             second: 0,
             minute: 0,
             hour: 0,
         }
     }
+
+    /// Returns the number of seconds since the start of the day.
+    fn as_seconds(&self) -> u32 {
+        self.hour * self.sizes.minutes * self.sizes.seconds
+            + self.minute * self.sizes.seconds
+            + self.second
+    }
+
+    /// Returns the level a timer that expires after `expires_in` goes to
+    /// and the second, minute and hour it expires at.
+    fn placement(&self, expires_in: Duration) -> (Level, u32, u32, u32) {
+        // TODO: timers that expire more than a day from now wrap around the hours wheel.
+        // The bucket of the current second has already been expired,
+        // so timers that expire in less than a second fire on the next tick.
+        let expires_in = expires_in.as_secs().max(1);
+        let expires_at =
+            (self.as_seconds() as u64 + expires_in) % (2 * self.sizes.horizon() as u64);
+        let (seconds, minutes, hours) = self.sizes.time_components(expires_at);
+
+        // The timer goes to the highest level whose clock has to move before the timer expires.
+        // Timers that expire the next day wait for the hours clock to go around,
+        // even if they expire at the current hour.
+        let level = if hours != self.hour {
+            Level::Hours
+        } else if minutes != self.minute {
+            Level::Minutes
+        } else {
+            Level::Seconds
+        };
+
+        (level, seconds, minutes, hours % self.sizes.hours)
+    }
 }
 
+type Bucket = Mutex<DoublyLinkedList<Timer>>;
+
 struct Buckets {
-    seconds: [DoublyLinkedList<Timer>; 60],
-    minutes: [DoublyLinkedList<Timer>; 60],
-    hours: [DoublyLinkedList<Timer>; 24],
+    seconds: Vec<Bucket>,
+    minutes: Vec<Bucket>,
+    hours: Vec<Bucket>,
 }
 
 impl Buckets {
-    fn new() -> Self {
+    fn new(sizes: LevelSizes) -> Self {
+        let level = |size| {
+            (0..size)
+                .map(|_| Mutex::new(DoublyLinkedList::new()))
+                .collect()
+        };
+
         Self {
-            seconds: [(); 60].map(|_| DoublyLinkedList::new()),
-            minutes: [(); 60].map(|_| DoublyLinkedList::new()),
-            hours: [(); 24].map(|_| DoublyLinkedList::new()),
+            seconds: level(sizes.seconds),
+            minutes: level(sizes.minutes),
+            hours: level(sizes.hours),
+        }
+    }
+
+    /// Returns the bucket `timer` belongs to in its current level.
+    fn bucket<'a>(&'a self, timer: &Timer) -> &'a Bucket {
+        match timer.level {
+            Level::Hours => &self.hours[timer.hours as usize],
+            Level::Minutes => &self.minutes[timer.minutes as usize],
+            Level::Seconds => &self.seconds[timer.seconds as usize],
         }
     }
 }
 
+/// Removes every node from `bucket` without freeing them.
+fn take_nodes(bucket: &Bucket) -> Vec<*mut Node<Timer>> {
+    let mut list = bucket.lock().unwrap();
+
+    let mut nodes = Vec::new();
+
+    let iter = list.iter_mut();
+    for node in iter {
+        list.unlink(node);
+        nodes.push(node);
+    }
+
+    nodes
+}
+
+/// Returns the timer in `node`.
+///
+/// # Safety
+///
+/// `node` must point to a node of the registry that has not been freed,
+/// and nothing else can use the timer while the returned reference is alive.
+unsafe fn timer<'a>(node: *mut Node<Timer>) -> &'a mut Timer {
+    unsafe { (*node).value.as_mut().unwrap() }
+}
+
+pub struct Builder {
+    sizes: LevelSizes,
+    on_cascade: Option<Arc<OnCascade>>,
+}
+
+impl Builder {
+    /// The number of buckets in the seconds wheel, which is also
+    /// the number of seconds each bucket in the minutes wheel covers.
+    pub fn num_second_buckets(mut self, num_second_buckets: u32) -> Self {
+        self.sizes.seconds = num_second_buckets;
+        self
+    }
+
+    /// The number of buckets in the minutes wheel, which is also
+    /// the number of minutes each bucket in the hours wheel covers.
+    pub fn num_minute_buckets(mut self, num_minute_buckets: u32) -> Self {
+        self.sizes.minutes = num_minute_buckets;
+        self
+    }
+
+    /// The number of buckets in the hours wheel.
+    pub fn num_hour_buckets(mut self, num_hour_buckets: u32) -> Self {
+        self.sizes.hours = num_hour_buckets;
+        self
+    }
+
+    /// Calls `on_cascade` every time a timer moves to a lower level of the hierarchy,
+    /// e.g. from the hours wheel to the minutes wheel.
+    ///
+    /// `on_cascade` is called while the clocks are locked so it must not use the registry.
+    pub fn on_cascade(mut self, on_cascade: impl Fn(CascadeEvent) + Send + Sync + 'static) -> Self {
+        self.on_cascade = Some(Arc::new(on_cascade));
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<Registry>, ConfigError> {
+        self.build_registry().map(Registry::start)
+    }
+
+    /// Builds a registry that is not ticked by a background thread.
+    /// Timers only expire when `expire_timers` is called.
+    pub fn build_registry(self) -> Result<Registry, ConfigError> {
+        let sizes = self.sizes;
+
+        for (level, size) in [
+            (Level::Seconds, sizes.seconds),
+            (Level::Minutes, sizes.minutes),
+            (Level::Hours, sizes.hours),
+        ] {
+            if size == 0 {
+                return Err(ConfigError::EmptyLevel(level));
+            }
+        }
+
+        if sizes
+            .seconds
+            .checked_mul(sizes.minutes)
+            .and_then(|seconds_in_an_hour| seconds_in_an_hour.checked_mul(sizes.hours))
+            .is_none()
+        {
+            return Err(ConfigError::HorizonTooLarge);
+        }
+
+        Ok(Registry {
+            id: NEXT_REGISTRY_ID.fetch_add(1, Ordering::Relaxed),
+            next_timer_id: AtomicU64::new(0),
+            pending: Mutex::new(HashSet::new()),
+            clocks: RwLock::new(Clocks::new(sizes)),
+            buckets: Buckets::new(sizes),
+            paused: AtomicBool::new(false),
+            ticks_missed_while_paused: AtomicU64::new(0),
+            on_cascade: self.on_cascade,
+        })
+    }
+}
+
 impl Registry {
     pub fn new() -> Arc<Self> {
-        let registry = Arc::new(Self {
-            state: Mutex::new(State {
-                clocks: Clocks::new(),
-                buckets: Buckets::new(),
-            }),
-        });
-        let registry_clone = Arc::downgrade(&registry);
-        std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
+        Self::builder()
+            .build()
+            .expect("the default level sizes are valid")
+    }
+
+    /// Creates a registry that calls `on_cascade` every time a timer moves
+    /// to a lower level of the hierarchy, e.g. from the hours wheel to the minutes wheel.
+    ///
+    /// `on_cascade` is called while the clocks are locked so it must not use the registry.
+    pub fn with_on_cascade(on_cascade: impl Fn(CascadeEvent) + Send + Sync + 'static) -> Arc<Self> {
+        Self::builder()
+            .on_cascade(on_cascade)
+            .build()
+            .expect("the default level sizes are valid")
+    }
+
+    pub fn builder() -> Builder {
+        Builder {
+            sizes: LevelSizes {
+                seconds: SECONDS_IN_A_MINUTE,
+                minutes: MINUTES_IN_A_HOUR,
+                hours: HOURS_IN_A_DAY,
+            },
+            on_cascade: None,
+        }
+    }
+
+    /// Spawns the thread that expires the timers in `registry`,
+    /// unless the `background-thread` feature is disabled.
+    fn start(registry: Self) -> Arc<Self> {
+        let registry = Arc::new(registry);
+
+        #[cfg(feature = "background-thread")]
+        {
+            let registry_clone = Arc::downgrade(&registry);
+            std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
+        }
+
         registry
     }
 
+    /// Creates a registry that is not ticked by a background thread.
+    #[cfg(test)]
+    fn without_bookkeeping() -> Self {
+        Self::builder()
+            .build_registry()
+            .expect("the default level sizes are valid")
+    }
+
+    /// Moves the timer in `node` to a bucket in a lower level of the hierarchy.
+    ///
+    /// The node itself is moved so handles to the timer stay valid.
+    fn cascade(&self, node: *mut Node<Timer>, to: Level) {
+        // SAFETY: the node was just taken out of a bucket while the clocks are write locked,
+        // so it is still pending and nothing else can reach it.
+        let timer = unsafe { timer(node) };
+
+        // Timers only move down, so they can't move more times than there are levels below
+        // the one they start in. More than that means the placement math is wrong and the
+        // timer could keep moving between levels without ever expiring.
+        debug_assert!(
+            timer.cascades < NUM_LEVELS - 1,
+            "timer {} moved from {:?} to {:?} after moving down {} times",
+            timer.id,
+            timer.level,
+            to,
+            timer.cascades
+        );
+        timer.cascades += 1;
+
+        if let Some(on_cascade) = &self.on_cascade {
+            on_cascade(CascadeEvent {
+                timer_id: timer.id,
+                from: timer.level,
+                to,
+            });
+        }
+
+        timer.level = to;
+        let bucket = self.buckets.bucket(timer);
+        bucket.lock().unwrap().push_back_node(node);
+    }
+
+    /// Frees `node`, returning the timer that expired.
+    fn expire(&self, node: *mut Node<Timer>) -> Timer {
+        let node = unsafe { Box::from_raw(node) };
+        let timer = node.value.unwrap();
+        // Expiring a timer twice means a node was left behind in a bucket it moved out of.
+        let was_pending = self.pending.lock().unwrap().remove(&timer.id);
+        debug_assert!(was_pending, "timer {} expired more than once", timer.id);
+        timer
+    }
+
+    /// Freezes the clocks: `expire_timers` does nothing until `resume` is called.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Unfreezes the clocks, handling the ticks that were missed
+    /// while the registry was paused according to `policy`.
+    pub fn resume(&self, policy: ResumePolicy) {
+        self.paused.store(false, Ordering::SeqCst);
+
+        let ticks_missed = self.ticks_missed_while_paused.swap(0, Ordering::SeqCst);
+
+        if policy == ResumePolicy::CatchUp {
+            for _ in 0..ticks_missed {
+                self.expire_timers();
+            }
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Sets the clocks to the UTC time of day of `now`, so timers land in the
+    /// slots of the real second, minute and hour they expire at.
+    ///
+    /// Timers that are already pending stay in their slots,
+    /// so the time left until they expire changes.
+    ///
+    /// Registries built with custom level sizes are set to the time since
+    /// the epoch modulo the time it takes their clocks to wrap around.
+    pub fn align_to(&self, now: SystemTime) {
+        let since_epoch = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+
+        let mut clocks = self.clocks.write().unwrap();
+
+        let horizon = clocks.sizes.horizon() as u64;
+        let (second, minute, hour) = clocks
+            .sizes
+            .time_components(since_epoch.as_secs() % horizon);
+
+        clocks.second = second;
+        clocks.minute = minute;
+        clocks.hour = hour;
+    }
+
+    /// Returns the time of day the clocks are at.
+    pub fn time_of_day(&self) -> Duration {
+        let clocks = self.clocks.read().unwrap();
+        Duration::from_secs(clocks.as_seconds() as u64)
+    }
+
+    /// Returns how much time each slot in `level` covers.
+    pub fn resolution(&self, level: Level) -> Duration {
+        self.clocks.read().unwrap().sizes.resolution(level)
+    }
+
+    /// Returns the resolution of the level a timer started now that expires after `expires_in`
+    /// expires from.
+    ///
+    /// Timers move to finer levels as they get closer to expiring, but only while the
+    /// finer level is needed to tell when they expire: a timer that expires on a whole minute
+    /// expires as soon as the minutes clock gets to it, without going through the seconds wheel.
+    pub fn effective_resolution(&self, expires_in: Duration) -> Duration {
+        let clocks = self.clocks.read().unwrap();
+
+        let (level, seconds, minutes, _) = clocks.placement(expires_in);
+
+        let expires_from = match level {
+            _ if seconds != 0 => Level::Seconds,
+            Level::Hours if minutes != 0 => Level::Minutes,
+            level => level,
+        };
+
+        clocks.sizes.resolution(expires_from)
+    }
+
+    /// Starts a timer that expires after `expires_in`, rounded down to a whole number of seconds.
+    /// It fires on the tick the clocks get to that second, or on the next tick
+    /// if it expires in less than a second.
+    ///
+    /// The clocks are read locked while the timer is placed, so starting a timer waits
+    /// for a tick that is moving the clocks and taking the expired timers out of the wheel.
+    /// Timers can be started while the actions of the expired timers run, since they run
+    /// without holding any lock, and timers that go to different buckets can be started
+    /// at the same time apart from the short lock on the set of pending timers.
     pub fn start_timer(
         &self,
         expires_in: Duration,
         expire_action: impl FnOnce() + Send + Sync + 'static,
     ) -> TimerHandle {
-        let mut state = self.state.lock().unwrap();
+        let clocks = self.clocks.read().unwrap();
+        self.insert_timer(&clocks, expires_in, Box::new(expire_action))
+    }
 
-        let expires_in_as_seconds = expires_in.as_secs() as u32;
+    /// Runs `action` every day when the clocks get to `hour`:`minute`:`second`,
+    /// until the returned `DailyTimer` is passed to `stop_daily`.
+    ///
+    /// A day is a whole turn of the hours wheel.
+    /// Call `align_to` first to run `action` at a time of day of the real clock.
+    pub fn schedule_daily_at(
+        self: &Arc<Self>,
+        hour: u32,
+        minute: u32,
+        second: u32,
+        action: impl FnMut() + Send + Sync + 'static,
+    ) -> DailyTimer {
+        let sizes = self.clocks.read().unwrap().sizes;
+        assert!(
+            hour < sizes.hours && minute < sizes.minutes && second < sizes.seconds,
+            "{hour}:{minute}:{second} is not a time of day"
+        );
+
+        let at = hour * sizes.minutes * sizes.seconds + minute * sizes.seconds + second;
+
+        let current = Arc::new(Mutex::new(None));
+
+        let mut handle = current.lock().unwrap();
+        *handle = Some(start_daily(self, at, Box::new(action), &current));
+        drop(handle);
+
+        DailyTimer { current }
+    }
 
-        let (seconds, minutes, hours) = time_components(expires_in_as_seconds);
+    /// Stops running the action of `daily_timer`.
+    pub fn stop_daily(&self, daily_timer: &DailyTimer) {
+        if let Some(handle) = daily_timer.current.lock().unwrap().take() {
+            self.stop_timer(&handle);
+        }
+    }
+
+    /// Adds a timer that expires after `expires_in`. `clocks` must be read locked.
+    fn insert_timer(
+        &self,
+        clocks: &Clocks,
+        expires_in: Duration,
+        expire_action: Box<ExpireAction>,
+    ) -> TimerHandle {
+        let (level, seconds, minutes, hours) = clocks.placement(expires_in);
+
+        let id = self.next_timer_id.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(id);
 
         let timer = Timer {
+            id,
+            level,
             seconds,
             minutes,
             hours,
-            expire_action: Some(Box::new(expire_action)),
+            cascades: 0,
+            expire_action: Some(expire_action),
         };
 
-        let node = if timer.hours > 0 {
-            let index = timer.hours as usize;
-            state.buckets.hours[index].push_back(timer)
-        } else if timer.minutes > 0 {
-            let index = timer.minutes as usize;
-            state.buckets.minutes[index].push_back(timer)
-        } else {
-            let index = timer.seconds as usize;
-            state.buckets.seconds[index].push_back(timer)
-        };
+        let bucket = self.buckets.bucket(&timer);
+        let node = bucket.lock().unwrap().push_back(timer);
 
-        TimerHandle { node }
+        TimerHandle {
+            registry_id: self.id,
+            id,
+            node,
+        }
     }
 
+    /// Stops the timer `timer_handle` points to.
+    ///
+    /// Does nothing if the timer has already expired or been stopped,
+    /// so actions can safely stop the timer that is running them,
+    /// or if the handle belongs to another registry.
     pub fn stop_timer(&self, timer_handle: &TimerHandle) {
-        let mut state = self.state.lock().unwrap();
+        if timer_handle.registry_id != self.id {
+            return;
+        }
 
-        let timer = unsafe { (*timer_handle.node).value.as_ref().unwrap() };
-        if timer.hours > 0 {
-            state.buckets.hours[timer.hours as usize].remove(timer_handle.node);
-        } else if timer.minutes > 0 {
-            state.buckets.minutes[timer.minutes as usize].remove(timer_handle.node);
-        } else {
-            state.buckets.seconds[timer.seconds as usize].remove(timer_handle.node);
+        // Keeps the timer from moving to another bucket while it is being removed.
+        let _clocks = self.clocks.read().unwrap();
+
+        if !self.pending.lock().unwrap().remove(&timer_handle.id) {
+            return;
         }
+
+        // SAFETY: the timer was pending in this registry, so its node has not been freed,
+        // and it was removed from `pending` so it can't expire or be stopped again.
+        let timer = unsafe { timer(timer_handle.node) };
+        let bucket = self.buckets.bucket(timer);
+        bucket.lock().unwrap().remove(timer_handle.node);
     }
 
-    pub fn expire_timers(&self) {
-        let mut state = self.state.lock().unwrap();
+    /// Advances the clocks by one tick and runs the actions of the timers that expired.
+    ///
+    /// Actions run without holding any lock, so they can start and stop timers.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn expire_timers(&self) -> usize {
+        if self.is_paused() {
+            self.ticks_missed_while_paused
+                .fetch_add(1, Ordering::SeqCst);
+            return 0;
+        }
+
+        let expired = self.advance_clocks(&mut self.clocks.write().unwrap());
+        let num_expired = expired.len();
 
-        let index = state.clocks.second as usize;
-        let iter = state.buckets.seconds[index].iter_mut();
-        for node in iter {
-            let node = state.buckets.seconds[index].remove(node);
-            let timer = node.value.unwrap();
+        for timer in expired {
             timer.expire_action.unwrap()();
         }
 
-        state.clocks.second = (state.clocks.second + 1) % SECONDS_IN_A_MINUTE;
-        // If 1 minute has not passed yet.
-        if state.clocks.second > 0 {
-            return;
-        }
+        num_expired
+    }
+
+    /// Advances `clocks` by one tick, moving timers to lower levels of the hierarchy
+    /// as their level's clock reaches them.
+    ///
+    /// Returns the timers that expire on the new tick. The clocks move before the seconds
+    /// bucket is expired, so every timer fires on the tick it expires at: the ones that
+    /// reach their slot as the minutes or hours clock moves fire right away and go first,
+    /// and the ones that move down to the seconds wheel go to a bucket that comes later.
+    fn advance_clocks(&self, clocks: &mut Clocks) -> Vec<Timer> {
+        clocks.second = (clocks.second + 1) % clocks.sizes.seconds;
+
+        let mut expired = if clocks.second == 0 {
+            self.advance_minutes(clocks)
+        } else {
+            Vec::new()
+        };
+
+        expired.extend(
+            take_nodes(&self.buckets.seconds[clocks.second as usize])
+                .into_iter()
+                .map(|node| self.expire(node)),
+        );
 
-        state.clocks.minute = (state.clocks.minute + 1) % MINUTES_IN_A_HOUR;
-        let index = state.clocks.minute as usize;
-        let iter = state.buckets.minutes[index].iter_mut();
-        for node in iter {
-            let node = state.buckets.minutes[index].remove(node);
-            let timer = node.value.unwrap();
+        expired
+    }
+
+    /// Advances the minutes clock, and the hours clock if it wraps around,
+    /// after the seconds clock wrapped around.
+    ///
+    /// Returns the timers that expire at the start of the new minute.
+    fn advance_minutes(&self, clocks: &mut Clocks) -> Vec<Timer> {
+        let mut expired = Vec::new();
+
+        clocks.minute = (clocks.minute + 1) % clocks.sizes.minutes;
+        for node in take_nodes(&self.buckets.minutes[clocks.minute as usize]) {
+            // SAFETY: the node was just taken out of its bucket and is still pending.
+            let seconds = unsafe { timer(node) }.seconds;
 
             // Timer has expired.
-            if timer.seconds == 0 {
-                timer.expire_action.unwrap()();
+            if seconds == 0 {
+                expired.push(self.expire(node));
             } else {
                 // The timer will expire in the future so we schedule it again
                 // but in a different bucket.
-                let index = timer.seconds as usize;
-                state.buckets.seconds[index].push_back(timer);
+                self.cascade(node, Level::Seconds);
             }
         }
 
         // If 1 hour has not passed yet.
-        if state.clocks.minute > 0 {
-            return;
+        if clocks.minute > 0 {
+            return expired;
         }
 
-        state.clocks.hour = (state.clocks.hour + 1) % HOURS_IN_A_DAY;
-        let index = state.clocks.hour as usize;
-        let iter = state.buckets.hours[index].iter_mut();
-        for node in iter {
-            let node = state.buckets.minutes[index].remove(node);
-            let timer = node.value.unwrap();
+        clocks.hour = (clocks.hour + 1) % clocks.sizes.hours;
+        for node in take_nodes(&self.buckets.hours[clocks.hour as usize]) {
+            // SAFETY: the node was just taken out of its bucket and is still pending.
+            let timer = unsafe { timer(node) };
 
             // Timer has expired.
             if timer.minutes == 0 && timer.seconds == 0 {
-                timer.expire_action.unwrap()();
+                expired.push(self.expire(node));
             } else if timer.minutes > 0 {
-                let index = timer.minutes as usize;
-                state.buckets.minutes[index].push_back(timer);
+                self.cascade(node, Level::Minutes);
             } else {
-                let index = timer.seconds as usize;
-                state.buckets.seconds[index].push_back(timer);
+                self.cascade(node, Level::Seconds);
             }
         }
+
+        expired
     }
 }
 
-fn time_components(secs: u32) -> (u32, u32, u32) {
-    let hours = secs / 3600;
-    let minutes = (secs % 3600) / 60;
-    let seconds = secs % 60;
-    (seconds, minutes, hours)
+/// Starts the timer for the next time the clocks get to `at` seconds since the start of the day.
+///
+/// The action of the timer starts the timer for the day after unless `current` was emptied
+/// by `Registry::stop_daily`.
+fn start_daily(
+    registry: &Arc<Registry>,
+    at: u32,
+    mut action: Box<DailyAction>,
+    current: &Arc<Mutex<Option<TimerHandle>>>,
+) -> TimerHandle {
+    let clocks = registry.clocks.read().unwrap();
+
+    // Computed every time instead of waiting a whole day so timers that fire
+    // a tick late don't drift.
+    let day = clocks.sizes.horizon() as u64;
+    let until_next = (at as u64 + day - clocks.as_seconds() as u64) % day;
+    let expires_in = Duration::from_secs(if until_next == 0 { day } else { until_next });
+
+    let registry_clone = Arc::downgrade(registry);
+    let current = Arc::clone(current);
+
+    registry.insert_timer(
+        &clocks,
+        expires_in,
+        Box::new(move || {
+            action();
+
+            let Some(registry) = registry_clone.upgrade() else {
+                return;
+            };
+
+            let mut handle = current.lock().unwrap();
+            if handle.is_some() {
+                *handle = Some(start_daily(&registry, at, action, &current));
+            }
+        }),
+    )
 }
 
+#[cfg(feature = "background-thread")]
 pub fn per_tick_bookkeeping(registry: Weak<Registry>) {
     loop {
+        // Every tick moves the clocks forward a second, so the first one happens a second in.
+        std::thread::sleep(Duration::from_secs(1));
+
         match registry.upgrade() {
             None => {
                 return;
             }
             Some(registry) => {
-                registry.expire_timers();
+                // Actions run without holding any lock, so a panicking action
+                // leaves the registry usable and only loses the timers that expired with it.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| registry.expire_timers()));
             }
         }
-
-        std::thread::sleep(Duration::from_secs(1));
     }
 }
 
 type ExpireAction = dyn FnOnce() + Send + Sync;
+type DailyAction = dyn FnMut() + Send + Sync;
 
 pub struct Timer {
+    id: u64,
+    /// The level of the bucket the timer is in.
+    level: Level,
+    /// The second the timer expires at.
     seconds: u32,
+    /// The minute the timer expires at.
     minutes: u32,
+    /// The hour the timer expires at.
     hours: u32,
+    /// The number of times the timer moved to a lower level.
+    cascades: u8,
     expire_action: Option<Box<ExpireAction>>,
 }
 
+/// A timer started with `Registry::schedule_daily_at`.
+pub struct DailyTimer {
+    /// The timer for the next run. `None` once the daily timer is stopped.
+    current: Arc<Mutex<Option<TimerHandle>>>,
+}
+
 /// Can be used to interact with a Timer after it has been registered.
 /// Could be used to cancel a timer for example.
 pub struct TimerHandle {
+    /// The registry that started the timer.
+    registry_id: u64,
+    /// The timer identifier.
+    /// Used to check that `node` has not been freed before using it.
+    id: u64,
     /// Node pointing to the timer in the bucket.
     node: *mut Node<Timer>,
 }
 
+// The node is only used after checking that the timer is still pending
+// in the registry that started it, which keeps it from being freed while it is used.
+unsafe impl Send for TimerHandle {}
+unsafe impl Sync for TimerHandle {}
+
+impl std::fmt::Debug for TimerHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Only the address is printed because the node is freed once the timer expires.
+        f.debug_struct("TimerHandle")
+            .field("node", &self.node)
+            .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::time::{Duration, Instant};
+    use std::{
+        panic::AssertUnwindSafe,
+        time::{Duration, Instant},
+    };
 
     use super::*;
 
     #[test]
+    fn time_components_at_the_boundaries_of_each_level() {
+        const NOON: (u32, u32, u32) = time_components(12 * 60 * 60);
+        assert_eq!((0, 0, 12), NOON);
+
+        assert_eq!((59, 0, 0), time_components(59));
+        assert_eq!((0, 1, 0), time_components(60));
+        assert_eq!((59, 59, 0), time_components(3599));
+        assert_eq!((0, 0, 1), time_components(3600));
+        assert_eq!((59, 59, 23), time_components(86399));
+        assert_eq!((0, 0, 0), time_components(86400));
+
+        assert_eq!((59, 59, 23, 0), time_components_with_days(86399));
+        assert_eq!((0, 0, 0, 1), time_components_with_days(86400));
+        assert_eq!((1, 1, 1, 2), time_components_with_days(2 * 86400 + 3661));
+
+        let sizes = LevelSizes {
+            seconds: SECONDS_IN_A_MINUTE,
+            minutes: MINUTES_IN_A_HOUR,
+            hours: HOURS_IN_A_DAY,
+        };
+        for secs in [0, 59, 60, 3599, 3600, 86399] {
+            assert_eq!(time_components(secs), sizes.time_components(secs as u64));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "background-thread")]
     fn simple() {
         let registry = Registry::new();
 
@@ -402,4 +1057,593 @@ mod tests {
 
         std::thread::sleep(Duration::from_secs(120));
     }
+
+    fn paused_registry_with_timer(fired: &Arc<AtomicBool>) -> Registry {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::clone(fired);
+        registry.start_timer(Duration::from_secs(2), move || {
+            fired.store(true, Ordering::SeqCst);
+        });
+
+        registry.pause();
+        for _ in 0..5 {
+            registry.expire_timers();
+        }
+
+        registry
+    }
+
+    #[test]
+    fn paused_registry_does_not_expire_timers() {
+        let fired = Arc::new(AtomicBool::new(false));
+        let registry = paused_registry_with_timer(&fired);
+        assert!(!fired.load(Ordering::SeqCst));
+
+        registry.resume(ResumePolicy::Continue);
+        assert!(!fired.load(Ordering::SeqCst));
+
+        registry.expire_timers();
+        assert!(!fired.load(Ordering::SeqCst));
+
+        registry.expire_timers();
+        assert!(fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn resume_catches_up_with_missed_ticks() {
+        let fired = Arc::new(AtomicBool::new(false));
+        let registry = paused_registry_with_timer(&fired);
+        assert!(!fired.load(Ordering::SeqCst));
+
+        registry.resume(ResumePolicy::CatchUp);
+        assert!(fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn timers_move_down_each_level_at_most_once() {
+        let mut registry = Registry::without_bookkeeping();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = Arc::clone(&events);
+        registry.on_cascade = Some(Arc::new(move |event| {
+            events_clone.lock().unwrap().push(event);
+        }));
+
+        let num_fired = Arc::new(AtomicU64::new(0));
+        let delays = [1, 59, 60, 61, 3599, 3600, 3601, 3661, 7322, 86399, 86400];
+        for secs in delays {
+            let num_fired = Arc::clone(&num_fired);
+            registry.start_timer(Duration::from_secs(secs), move || {
+                num_fired.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        for _ in 0..=86400 {
+            registry.expire_timers();
+        }
+        assert_eq!(delays.len() as u64, num_fired.load(Ordering::SeqCst));
+
+        let rank = |level| match level {
+            Level::Seconds => 0,
+            Level::Minutes => 1,
+            Level::Hours => 2,
+        };
+
+        let events = events.lock().unwrap();
+        for timer_id in 0..delays.len() as u64 {
+            let from: Vec<Level> = events
+                .iter()
+                .filter(|event| event.timer_id == timer_id)
+                .inspect(|event| assert!(rank(event.to) < rank(event.from), "{event:?}"))
+                .map(|event| event.from)
+                .collect();
+
+            assert!(
+                from.len() < NUM_LEVELS as usize,
+                "timer {timer_id} moved {from:?}"
+            );
+            assert!(from.windows(2).all(|pair| rank(pair[1]) < rank(pair[0])));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "after moving down 2 times")]
+    fn moving_down_more_times_than_there_are_levels_is_caught_in_debug_builds() {
+        let registry = Registry::without_bookkeeping();
+
+        registry.start_timer(Duration::from_secs(3661), || {});
+        // Pretend the timer already moved down every level.
+        for node in take_nodes(&registry.buckets.hours[1]) {
+            unsafe { timer(node) }.cascades = 2;
+            registry.buckets.hours[1]
+                .lock()
+                .unwrap()
+                .push_back_node(node);
+        }
+
+        for _ in 0..3600 {
+            registry.expire_timers();
+        }
+    }
+
+    #[test]
+    fn on_cascade_is_called_for_every_level_transition() {
+        let mut registry = Registry::without_bookkeeping();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = Arc::clone(&events);
+        registry.on_cascade = Some(Arc::new(move |event| {
+            events_clone.lock().unwrap().push(event);
+        }));
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired_clone = Arc::clone(&fired);
+        // 1 hour, 1 minute and 1 second.
+        registry.start_timer(Duration::from_secs(3661), move || {
+            fired_clone.store(true, Ordering::SeqCst);
+        });
+        // Expires without moving to another level.
+        registry.start_timer(Duration::from_secs(5), || {});
+
+        for _ in 0..3662 {
+            registry.expire_timers();
+        }
+
+        assert!(fired.load(Ordering::SeqCst));
+        assert_eq!(
+            vec![
+                CascadeEvent {
+                    timer_id: 0,
+                    from: Level::Hours,
+                    to: Level::Minutes
+                },
+                CascadeEvent {
+                    timer_id: 0,
+                    from: Level::Minutes,
+                    to: Level::Seconds
+                },
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn timers_land_in_the_slots_of_the_aligned_clock() {
+        let registry = Registry::without_bookkeeping();
+
+        // 10:59:30 UTC.
+        let ten_fifty_nine = Duration::from_secs(10 * 3600 + 59 * 60 + 30);
+        registry.align_to(SystemTime::UNIX_EPOCH + Duration::from_secs(3 * 86400) + ten_fifty_nine);
+        assert_eq!(ten_fifty_nine, registry.time_of_day());
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired_clone = Arc::clone(&fired);
+        // Expires at 11:09:30.
+        registry.start_timer(Duration::from_secs(10 * 60), move || {
+            fired_clone.store(true, Ordering::SeqCst);
+        });
+
+        for _ in 0..599 {
+            registry.expire_timers();
+        }
+        assert!(!fired.load(Ordering::SeqCst));
+
+        registry.expire_timers();
+        assert!(fired.load(Ordering::SeqCst));
+        assert_eq!(
+            Duration::from_secs(11 * 3600 + 9 * 60 + 30),
+            registry.time_of_day()
+        );
+    }
+
+    #[test]
+    fn handles_from_another_registry_are_ignored() {
+        let registry = Registry::without_bookkeeping();
+        let other = Registry::without_bookkeeping();
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(1), move || {
+            fired_clone.store(true, Ordering::SeqCst);
+        });
+        // Gets the same timer id as the timer in `registry`.
+        let other_handle = other.start_timer(Duration::from_secs(1), || {});
+
+        registry.stop_timer(&other_handle);
+
+        assert_eq!(1, registry.expire_timers());
+        assert!(fired.load(Ordering::SeqCst));
+        assert_eq!(1, other.expire_timers());
+    }
+
+    #[test]
+    fn timer_handle_debug_prints_node_address() {
+        let registry = Registry::without_bookkeeping();
+
+        let handle = registry.start_timer(Duration::from_secs(1), || {});
+
+        assert_eq!(
+            format!("TimerHandle {{ node: {:p} }}", handle.node),
+            format!("{:?}", handle)
+        );
+    }
+
+    #[test]
+    fn timers_started_while_ticking_fire_exactly_once() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+
+        const NUM_THREADS: usize = 4;
+        const TIMERS_PER_THREAD: usize = 500;
+
+        let fired: Arc<Vec<AtomicU64>> = Arc::new(
+            (0..NUM_THREADS * TIMERS_PER_THREAD)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        );
+        let done = Arc::new(AtomicBool::new(false));
+
+        let ticker = {
+            let registry = Arc::clone(&registry);
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    registry.expire_timers();
+                }
+            })
+        };
+
+        let starters: Vec<_> = (0..NUM_THREADS)
+            .map(|thread| {
+                let registry = Arc::clone(&registry);
+                let fired = Arc::clone(&fired);
+                std::thread::spawn(move || {
+                    for i in 0..TIMERS_PER_THREAD {
+                        let timer = thread * TIMERS_PER_THREAD + i;
+                        let fired = Arc::clone(&fired);
+                        // Covers every level of the hierarchy.
+                        let expires_in = (timer as u64 * 37) % 7300;
+                        registry.start_timer(Duration::from_secs(expires_in), move || {
+                            fired[timer].fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                })
+            })
+            .collect();
+
+        for starter in starters {
+            starter.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        ticker.join().unwrap();
+
+        for _ in 0..7301 {
+            registry.expire_timers();
+        }
+
+        for (timer, fired) in fired.iter().enumerate() {
+            assert_eq!(1, fired.load(Ordering::SeqCst), "timer {timer}");
+        }
+    }
+
+    #[test]
+    fn expire_timers_returns_the_number_of_actions_that_ran() {
+        let registry = Registry::without_bookkeeping();
+
+        for expires_in in [1, 1, 60] {
+            registry.start_timer(Duration::from_secs(expires_in), || {});
+        }
+
+        assert_eq!(2, registry.expire_timers());
+
+        let num_expired: usize = (0..58).map(|_| registry.expire_timers()).sum();
+        assert_eq!(0, num_expired);
+        assert_eq!(1, registry.expire_timers());
+    }
+
+    /// Starts a timer that starts itself again every time it expires
+    /// and stops itself the third time.
+    fn start_self_rescheduling_timer(
+        registry: &Arc<Registry>,
+        fired: &Arc<AtomicU64>,
+        next_handle: &Arc<Mutex<Option<TimerHandle>>>,
+    ) {
+        let registry_clone = Arc::clone(registry);
+        let fired_clone = Arc::clone(fired);
+        let next_handle_clone = Arc::clone(next_handle);
+
+        let handle = registry.start_timer(Duration::from_secs(1), move || {
+            let running = next_handle_clone.lock().unwrap().take().unwrap();
+
+            start_self_rescheduling_timer(&registry_clone, &fired_clone, &next_handle_clone);
+
+            if fired_clone.fetch_add(1, Ordering::SeqCst) + 1 == 3 {
+                // The running timer has already expired so this does nothing.
+                registry_clone.stop_timer(&running);
+
+                let next = next_handle_clone.lock().unwrap().take().unwrap();
+                registry_clone.stop_timer(&next);
+            }
+        });
+
+        *next_handle.lock().unwrap() = Some(handle);
+    }
+
+    #[test]
+    fn action_can_stop_and_start_its_own_timer() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+        let fired = Arc::new(AtomicU64::new(0));
+        let next_handle = Arc::new(Mutex::new(None));
+
+        start_self_rescheduling_timer(&registry, &fired, &next_handle);
+
+        for _ in 0..10 {
+            registry.expire_timers();
+        }
+
+        assert_eq!(3, fired.load(Ordering::SeqCst));
+        assert!(registry.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn effective_resolution_depends_on_the_level_timers_expire_from() {
+        let registry = Registry::without_bookkeeping();
+
+        assert_eq!(
+            Duration::from_secs(60),
+            registry.effective_resolution(Duration::from_secs(90 * 60))
+        );
+        assert_eq!(
+            Duration::from_secs(3600),
+            registry.effective_resolution(Duration::from_secs(2 * 3600))
+        );
+        assert_eq!(
+            Duration::from_secs(1),
+            registry.effective_resolution(Duration::from_secs(90 * 60 + 1))
+        );
+        assert_eq!(
+            Duration::from_secs(1),
+            registry.effective_resolution(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn builder_rejects_invalid_level_sizes() {
+        let error = |builder: Builder| builder.build_registry().err().unwrap();
+
+        assert_eq!(
+            ConfigError::EmptyLevel(Level::Seconds),
+            error(Registry::builder().num_second_buckets(0))
+        );
+        assert_eq!(
+            ConfigError::EmptyLevel(Level::Minutes),
+            error(Registry::builder().num_minute_buckets(0))
+        );
+        assert_eq!(
+            ConfigError::EmptyLevel(Level::Hours),
+            error(Registry::builder().num_hour_buckets(0))
+        );
+        assert_eq!(
+            ConfigError::HorizonTooLarge,
+            error(
+                Registry::builder()
+                    .num_second_buckets(1 << 16)
+                    .num_minute_buckets(1 << 8)
+                    .num_hour_buckets(1 << 8)
+            )
+        );
+
+        assert_eq!(
+            "Minutes level has no buckets",
+            ConfigError::EmptyLevel(Level::Minutes).to_string()
+        );
+    }
+
+    #[test]
+    fn timers_fire_on_time_with_custom_level_sizes() {
+        let registry = Registry::builder()
+            .num_second_buckets(10)
+            .num_minute_buckets(5)
+            .num_hour_buckets(3)
+            .build_registry()
+            .unwrap();
+
+        assert_eq!(Duration::from_secs(50), registry.resolution(Level::Hours));
+
+        let ticks = Arc::new(AtomicU64::new(0));
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for expires_in in [3, 10, 27, 73, 149] {
+            let ticks = Arc::clone(&ticks);
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired
+                    .lock()
+                    .unwrap()
+                    .push((expires_in, ticks.load(Ordering::SeqCst)));
+            });
+        }
+
+        for _ in 0..151 {
+            ticks.fetch_add(1, Ordering::SeqCst);
+            registry.expire_timers();
+        }
+
+        // Like with the default sizes, timers fire on the tick they expire at
+        // no matter which level they expire from.
+        assert_eq!(
+            vec![(3, 3), (10, 10), (27, 27), (73, 73), (149, 149)],
+            *fired.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn daily_timer_fires_at_the_same_time_every_day() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+
+        // 23:59:50 UTC.
+        registry.align_to(SystemTime::UNIX_EPOCH + Duration::from_secs(86400 - 10));
+
+        let fired_at = Arc::new(Mutex::new(Vec::new()));
+        let fired_at_clone = Arc::clone(&fired_at);
+        let registry_clone = Arc::downgrade(&registry);
+        let daily = registry.schedule_daily_at(0, 1, 0, move || {
+            let registry = registry_clone.upgrade().unwrap();
+            fired_at_clone.lock().unwrap().push(registry.time_of_day());
+        });
+
+        let one_past_midnight = Duration::from_secs(60);
+
+        for _ in 0..70 {
+            registry.expire_timers();
+        }
+        assert_eq!(vec![one_past_midnight], *fired_at.lock().unwrap());
+
+        for _ in 0..86400 {
+            registry.expire_timers();
+        }
+        assert_eq!(
+            vec![one_past_midnight, one_past_midnight],
+            *fired_at.lock().unwrap()
+        );
+
+        registry.stop_daily(&daily);
+        for _ in 0..86400 {
+            registry.expire_timers();
+        }
+        assert_eq!(2, fired_at.lock().unwrap().len());
+        assert!(registry.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn timer_that_expires_in_a_day_waits_for_the_hours_wheel_to_go_around() {
+        let registry = Registry::without_bookkeeping();
+
+        // 10:30:00 UTC.
+        registry.align_to(SystemTime::UNIX_EPOCH + Duration::from_secs(10 * 3600 + 30 * 60));
+
+        for expires_in in [86400, 86400 - 60] {
+            registry.start_timer(Duration::from_secs(expires_in), || {});
+        }
+
+        let num_expired: usize = (0..86400 - 60).map(|_| registry.expire_timers()).sum();
+        assert_eq!(1, num_expired);
+
+        let num_expired: usize = (0..60).map(|_| registry.expire_timers()).sum();
+        assert_eq!(1, num_expired);
+    }
+
+    #[test]
+    fn registry_keeps_ticking_after_an_action_panics() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(AtomicU64::new(0));
+
+        registry.start_timer(Duration::from_secs(1), || panic!("action panicked"));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(2), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let mut panics = 0;
+        for _ in 0..4 {
+            if std::panic::catch_unwind(AssertUnwindSafe(|| registry.expire_timers())).is_err() {
+                panics += 1;
+            }
+        }
+
+        assert_eq!(1, panics);
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[cfg(not(feature = "background-thread"))]
+    fn registries_are_only_ticked_by_hand_without_a_background_thread() {
+        let registry = Registry::new();
+
+        let fired = Arc::new(AtomicU64::new(0));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(1), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(0, fired.load(Ordering::SeqCst));
+
+        let mut ticks = 0;
+        while fired.load(Ordering::SeqCst) == 0 {
+            registry.expire_timers();
+            ticks += 1;
+            assert!(ticks <= 2, "the timer never fired");
+        }
+    }
+
+    #[test]
+    fn every_timer_fires_exactly_once() {
+        let registry = Registry::without_bookkeeping();
+
+        const NUM_TIMERS: usize = 10_000;
+        let fired: Arc<Vec<AtomicU64>> =
+            Arc::new((0..NUM_TIMERS).map(|_| AtomicU64::new(0)).collect());
+
+        // xorshift64, so every run uses the same delays.
+        let mut random = 0x9E37_79B9_7F4A_7C15_u64;
+        for id in 0..NUM_TIMERS {
+            random ^= random << 13;
+            random ^= random >> 7;
+            random ^= random << 17;
+            let delay = Duration::from_millis(random % (86_400 * 1000));
+
+            let fired = Arc::clone(&fired);
+            registry.start_timer(delay, move || {
+                fired[id].fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        for _ in 0..86_400 + 2 {
+            registry.expire_timers();
+        }
+
+        for (id, fired) in fired.iter().enumerate() {
+            assert_eq!(1, fired.load(Ordering::SeqCst), "timer {id}");
+        }
+        assert!(registry.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn timers_that_move_down_on_a_tick_fire_on_the_tick_they_expire_at() {
+        let registry = Registry::without_bookkeeping();
+
+        let ticks = Arc::new(AtomicU64::new(0));
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        // 60 and 3600 reach their slot as the minutes and hours clocks move,
+        // 61 and 3601 move down to the seconds wheel first.
+        for expires_in in [1, 59, 60, 61, 3600, 3601] {
+            let ticks = Arc::clone(&ticks);
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired
+                    .lock()
+                    .unwrap()
+                    .push((expires_in, ticks.load(Ordering::SeqCst)));
+            });
+        }
+
+        for _ in 0..3602 {
+            ticks.fetch_add(1, Ordering::SeqCst);
+            registry.expire_timers();
+        }
+
+        assert_eq!(
+            vec![
+                (1, 1),
+                (59, 59),
+                (60, 60),
+                (61, 61),
+                (3600, 3600),
+                (3601, 3601)
+            ],
+            *fired.lock().unwrap()
+        );
+    }
 }
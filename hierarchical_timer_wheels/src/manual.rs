@@ -0,0 +1,154 @@
+//! A registry that is only ticked by its owner.
+//!
+//! `ManualRegistry` owns a `Registry` that has no background thread and hands out
+//! handles that are plain ids, so they can be stored and sent anywhere without
+//! pointing into the wheel.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{Builder, ConfigError, Registry, TimerHandle};
+
+pub struct ManualRegistry {
+    registry: Registry,
+    /// The id of the next timer. Not the id the registry gives the timer,
+    /// which is only known once it has been started.
+    next_timer_id: AtomicU64,
+    /// The handle of every pending timer by the id handed out for it.
+    /// Timers remove themselves when they expire.
+    handles: Arc<Mutex<HashMap<u64, TimerHandle>>>,
+}
+
+/// Points to a timer started with `ManualRegistry::start_timer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ManualTimerHandle {
+    id: u64,
+}
+
+impl ManualRegistry {
+    /// Creates a registry with the default level sizes.
+    pub fn new() -> Self {
+        Self::from_builder(Registry::builder()).expect("the default level sizes are valid")
+    }
+
+    /// Creates a registry with the level sizes set on `builder`.
+    pub fn from_builder(builder: Builder) -> Result<Self, ConfigError> {
+        Ok(Self {
+            registry: builder.build_registry()?,
+            next_timer_id: AtomicU64::new(0),
+            handles: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Starts a timer that expires after `expires_in`, like `Registry::start_timer`.
+    pub fn start_timer(
+        &self,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> ManualTimerHandle {
+        let id = self.next_timer_id.fetch_add(1, Ordering::Relaxed);
+
+        // Held until the handle is stored, so a tick on another thread can't run
+        // the action and remove the entry before it is inserted.
+        let mut handles = self.handles.lock().unwrap();
+
+        let handles_clone = Arc::clone(&self.handles);
+        let timer_handle = self.registry.start_timer(expires_in, move || {
+            handles_clone.lock().unwrap().remove(&id);
+            expire_action();
+        });
+        handles.insert(id, timer_handle);
+
+        ManualTimerHandle { id }
+    }
+
+    /// Stops the timer `timer_handle` points to.
+    ///
+    /// Does nothing if the timer has already expired or been stopped.
+    pub fn stop_timer(&self, timer_handle: &ManualTimerHandle) {
+        if let Some(handle) = self.handles.lock().unwrap().remove(&timer_handle.id) {
+            self.registry.stop_timer(&handle);
+        }
+    }
+
+    /// Returns true if the timer `timer_handle` points to has not expired or been stopped.
+    pub fn contains(&self, timer_handle: &ManualTimerHandle) -> bool {
+        self.handles.lock().unwrap().contains_key(&timer_handle.id)
+    }
+
+    /// Advances the clocks by one tick and runs the actions of the timers that expired.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn tick(&self) -> usize {
+        self.registry.expire_timers()
+    }
+}
+
+impl Default for ManualRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timers_expire_when_ticked_and_stop_by_handle() {
+        let registry = ManualRegistry::new();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let mut handles = Vec::new();
+        for expires_in in [3, 1, 62, 2] {
+            let fired = Arc::clone(&fired);
+            handles.push(
+                registry.start_timer(Duration::from_secs(expires_in), move || {
+                    fired.lock().unwrap().push(expires_in);
+                }),
+            );
+        }
+
+        // Handles are plain ids, so they can go to another thread.
+        let stopped = handles[3];
+        std::thread::scope(|scope| {
+            scope.spawn(|| registry.stop_timer(&stopped));
+        });
+        assert!(!registry.contains(&stopped));
+
+        let fired_per_tick: Vec<usize> = (0..63).map(|_| registry.tick()).collect();
+        assert_eq!(3, fired_per_tick.iter().sum::<usize>());
+        assert_eq!(vec![1, 3, 62], *fired.lock().unwrap());
+
+        // Expired timers don't leave their handle behind, and stopping them does nothing.
+        assert!(handles.iter().all(|handle| !registry.contains(handle)));
+        assert!(registry.handles.lock().unwrap().is_empty());
+        registry.stop_timer(&handles[0]);
+    }
+
+    #[test]
+    fn level_sizes_come_from_the_builder() {
+        let registry =
+            ManualRegistry::from_builder(Registry::builder().num_second_buckets(10)).unwrap();
+
+        // Past the end of the seconds wheel, so the timer starts in the minutes wheel.
+        let handle = registry.start_timer(Duration::from_secs(25), || {});
+        for _ in 0..24 {
+            registry.tick();
+        }
+        assert!(registry.contains(&handle));
+        assert_eq!(1, registry.tick());
+        assert!(!registry.contains(&handle));
+
+        assert!(matches!(
+            ManualRegistry::from_builder(Registry::builder().num_minute_buckets(0)),
+            Err(ConfigError::EmptyLevel(_))
+        ));
+    }
+}
@@ -2,73 +2,735 @@
 
 use std::{
     cmp::{Ordering, Reverse},
-    collections::BinaryHeap,
-    sync::{Arc, Mutex, Weak},
+    collections::{BinaryHeap, HashSet},
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak,
+        atomic::{self, AtomicU64},
+        mpsc,
+    },
+    task::{Context, Poll, Waker},
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
 pub struct Registry {
+    /// The longest the background thread sleeps between checks for expired timers.
+    /// `None` means it sleeps until the next timer expires.
+    resolution: Option<Duration>,
+    /// Wakes the background thread up when the timers change.
+    wakeup: Arc<Wakeup>,
+    /// The maximum number of timers that can be pending at the same time.
+    max_timers: Option<usize>,
+    /// The id of the timer started by the next `Sleep`.
+    next_sleep_id: AtomicU64,
+    /// The namespace of the next `ChildRegistry`.
+    next_child_id: AtomicU64,
+    /// Where the registry gets the current time from.
+    clock: Clock,
+    /// Set once the background thread starts ticking.
+    started: Mutex<bool>,
+    started_changed: Condvar,
     timers: Mutex<BinaryHeap<Reverse<Timer>>>,
+    /// Runs the actions of expired timers instead of the thread that expires them.
+    /// Shut down after everything else so queued actions can still use the registry.
+    workers: Option<WorkerPool>,
+}
+
+/// The threads that run actions for registries created with `Registry::with_worker_pool`.
+struct WorkerPool {
+    /// Taken when the pool shuts down, so the workers stop once the queued actions ran.
+    sender: Option<mpsc::Sender<Box<ExpireAction>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    fn new(num_workers: usize) -> Self {
+        assert!(num_workers > 0, "the pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::channel::<Box<ExpireAction>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..num_workers)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::spawn(move || {
+                    loop {
+                        // The receiver is unlocked before the action runs
+                        // so the other workers can take the next one.
+                        let action = receiver
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .recv();
+
+                        match action {
+                            // A panicking action doesn't take the worker down with it.
+                            Ok(action) => {
+                                let _ = std::panic::catch_unwind(AssertUnwindSafe(action));
+                            }
+                            Err(mpsc::RecvError) => return,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    fn run(&self, action: Box<ExpireAction>) {
+        // The sender is only taken when the pool is dropped.
+        let _ = self.sender.as_ref().unwrap().send(action);
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        let current = std::thread::current().id();
+        for worker in self.workers.drain(..) {
+            // The last reference to the registry can be dropped by one of its actions.
+            if worker.thread().id() != current {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+/// A clock that only moves when it is told to.
+///
+/// Makes it possible to expire timers without waiting for real time to pass.
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    /// Creates a clock that starts at the current time.
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap_or_else(PoisonError::into_inner) += by;
+    }
+
+    /// Moves the clock backward by `by`, like a system clock being set back.
+    pub fn rewind(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(PoisonError::into_inner);
+        *now = now.checked_sub(by).unwrap_or(*now);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lets the background thread sleep until the next timer expires, or until a timer
+/// is started if there are none, without keeping the registry alive.
+struct Wakeup {
+    state: Mutex<WakeupState>,
+    changed: Condvar,
+}
+
+struct WakeupState {
+    /// Set when the timers change, so changes made while the background thread
+    /// was not waiting are not missed.
+    notified: bool,
+    /// Whether the background thread is waiting without a timeout.
+    parked: bool,
+}
+
+impl Wakeup {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(WakeupState {
+                notified: false,
+                parked: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn notify(&self) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .notified = true;
+        self.changed.notify_all();
+    }
+
+    /// Blocks until `notify` is called or `timeout` passes.
+    /// Blocks until `notify` is called if there is no `timeout`.
+    fn wait(&self, timeout: Option<Duration>) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match timeout {
+            None => {
+                state.parked = true;
+                state = self
+                    .changed
+                    .wait_while(state, |state| !state.notified)
+                    .unwrap_or_else(PoisonError::into_inner);
+                state.parked = false;
+            }
+            Some(timeout) => {
+                state = self
+                    .changed
+                    .wait_timeout_while(state, timeout, |state| !state.notified)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+        }
+
+        state.notified = false;
+    }
+
+    fn is_parked(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .parked
+    }
+}
+
+enum Clock {
+    /// Uses `Instant::now`, which never goes backward,
+    /// so changing the system time does not make timers expire early or late.
+    System,
+    Manual(Arc<ManualClock>, Mutex<ManualTime>),
+}
+
+/// The registry time when using a `ManualClock`.
+struct ManualTime {
+    /// The time the clock was at the last time it was read.
+    last_read: Instant,
+    now: Instant,
+}
+
+impl Clock {
+    fn manual(clock: Arc<ManualClock>) -> Self {
+        let now = clock.now();
+        Clock::Manual(
+            clock,
+            Mutex::new(ManualTime {
+                last_read: now,
+                now,
+            }),
+        )
+    }
+
+    fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Manual(clock, time) => {
+                let mut time = time.lock().unwrap_or_else(PoisonError::into_inner);
+                let read = clock.now();
+
+                // Time the clock was moved back by is ignored, so the registry time
+                // stands still instead of going backward and delaying every timer.
+                let elapsed = read.saturating_duration_since(time.last_read);
+                time.now += elapsed;
+                time.last_read = read;
+
+                time.now
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// A thread panicked while holding the registry lock.
+    /// Call `Registry::clear_poison` to keep using the registry.
+    /// Registries with a background thread clear it the next time the thread wakes up.
+    Poisoned,
+    /// The registry already holds the maximum number of timers.
+    CapacityExceeded,
+    /// There is no pending timer with the given id.
+    NotFound,
+    /// The registry was dropped before the timer expired.
+    RegistryDropped,
+}
+
+impl std::fmt::Display for TimerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimerError::Poisoned => write!(f, "registry lock is poisoned"),
+            TimerError::CapacityExceeded => write!(f, "registry is full"),
+            TimerError::NotFound => write!(f, "timer not found"),
+            TimerError::RegistryDropped => write!(f, "registry was dropped"),
+        }
+    }
+}
+
+impl std::error::Error for TimerError {}
+
+impl<T> From<PoisonError<T>> for TimerError {
+    fn from(_: PoisonError<T>) -> Self {
+        TimerError::Poisoned
+    }
 }
 
 impl Registry {
     pub fn new() -> Arc<Self> {
-        let registry = Arc::new(Self {
-            timers: Mutex::new(BinaryHeap::new()),
-        });
+        Self::start(Self::without_bookkeeping())
+    }
+
+    /// Creates a registry that holds at most `max_timers` pending timers.
+    pub fn with_max_timers(max_timers: usize) -> Arc<Self> {
+        let mut registry = Self::without_bookkeeping();
+        registry.max_timers = Some(max_timers);
+        Self::start(registry)
+    }
+
+    /// Creates a registry that reads the current time from `clock` instead of the system clock.
+    pub fn with_clock(clock: Arc<ManualClock>) -> Arc<Self> {
+        let mut registry = Self::without_bookkeeping();
+        registry.clock = Clock::manual(clock);
+        Self::start(registry)
+    }
+
+    /// Creates a registry that checks for expired timers at least every `resolution`
+    /// while there are pending timers.
+    ///
+    /// Registries sleep until the next timer expires by default, which only goes wrong
+    /// if the clock jumps, e.g. with a `ManualClock`.
+    pub fn with_resolution(resolution: Duration) -> Arc<Self> {
+        let mut registry = Self::without_bookkeeping();
+        registry.resolution = Some(resolution);
+        Self::start(registry)
+    }
+
+    /// Creates a registry that runs actions on `num_workers` threads of its own,
+    /// so slow actions don't hold up the timers and actions that expire together run in parallel.
+    ///
+    /// Actions that are still queued when the registry is dropped run before the workers stop.
+    pub fn with_worker_pool(num_workers: usize) -> Arc<Self> {
+        let mut registry = Self::without_bookkeeping();
+        registry.workers = Some(WorkerPool::new(num_workers));
+        Self::start(registry)
+    }
+
+    /// Spawns the thread that expires the timers in `registry`.
+    fn start(registry: Self) -> Arc<Self> {
+        let registry = Arc::new(registry);
         let registry_clone = Arc::downgrade(&registry);
         std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
         registry
     }
 
+    /// Creates a registry that is not ticked by a background thread.
+    /// Timers only expire when `expire_timers` is called.
+    pub fn without_bookkeeping() -> Self {
+        Self {
+            resolution: None,
+            wakeup: Arc::new(Wakeup::new()),
+            max_timers: None,
+            next_sleep_id: AtomicU64::new(0),
+            next_child_id: AtomicU64::new(0),
+            clock: Clock::System,
+            started: Mutex::new(false),
+            started_changed: Condvar::new(),
+            timers: Mutex::new(BinaryHeap::new()),
+            workers: None,
+        }
+    }
+
+    /// Blocks until the background thread that expires timers has started ticking.
+    pub fn wait_until_started(&self) {
+        let started = self.started.lock().unwrap_or_else(PoisonError::into_inner);
+        let _started = self
+            .started_changed
+            .wait_while(started, |started| !*started)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+
+    fn mark_started(&self) {
+        *self.started.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.started_changed.notify_all();
+    }
+
+    /// Returns true if the background thread is asleep until a timer is started.
+    pub fn is_idle(&self) -> bool {
+        self.wakeup.is_parked()
+    }
+
+    /// Returns how long the background thread can sleep before it has to expire timers again.
+    /// Returns `None` if there are no timers.
+    fn time_until_next_check(&self) -> Option<Duration> {
+        let timers = lock_clearing_poison(&self.timers);
+
+        let Reverse(next_timer) = timers.peek()?;
+        let until_next_timer = next_timer.expires_at.saturating_duration_since(self.now());
+
+        Some(match self.resolution {
+            None => until_next_timer,
+            Some(resolution) => until_next_timer.min(resolution),
+        })
+    }
+
+    /// Returns the current time according to the registry clock.
+    ///
+    /// Never goes backward, even if a `ManualClock` is rewound.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Moves the registry clock forward by `by` and expires the timers that are due.
+    ///
+    /// Panics if the registry was not created with `Registry::with_clock`.
+    pub fn advance(&self, by: Duration) -> Result<(), TimerError> {
+        let Clock::Manual(clock, _) = &self.clock else {
+            panic!("only registries created with a ManualClock can be advanced");
+        };
+
+        clock.advance(by);
+
+        self.expire_timers(self.now())
+    }
+
+    /// Expires every timer that is due by `target`, earliest first, in a single call.
+    ///
+    /// Registries created with `Registry::with_clock` move their clock forward to `target` first.
+    pub fn advance_to(&self, target: Instant) -> Result<(), TimerError> {
+        match &self.clock {
+            Clock::System => self.expire_timers(target),
+            Clock::Manual(clock, _) => {
+                clock.advance(target.saturating_duration_since(self.now()));
+                self.expire_timers(self.now())
+            }
+        }
+    }
+
+    /// Makes the registry usable again after a thread panicked while holding its lock.
+    ///
+    /// The timers are always left in a consistent state because
+    /// they are never modified while an action is running.
+    pub fn clear_poison(&self) {
+        self.timers.clear_poison();
+        self.wakeup.notify();
+    }
+
+    /// Starts a timer that expires at `expires_at`. Timers whose deadline already passed
+    /// expire the next time the timers are checked.
+    ///
+    /// Returns the instant the timer was scheduled for.
     pub fn start_timer(
         &self,
-        id: u64,
+        id: TimerId,
         expires_at: Instant,
         expire_action: impl FnOnce() + Send + Sync + 'static,
-    ) {
-        let mut timers = self.timers.lock().unwrap();
+    ) -> Result<Instant, TimerError> {
+        self.insert_timer(Owner::User, id, expires_at, Box::new(expire_action))?;
+        Ok(expires_at)
+    }
+
+    /// Starts a timer taken out of a registry with `drain_all` again,
+    /// in this registry or another one, without boxing its action a second time.
+    ///
+    /// Stopping a timer removes it right away, so an id that was stopped
+    /// can be reinserted like any other.
+    pub fn reinsert(
+        &self,
+        id: TimerId,
+        expires_at: Instant,
+        expire_action: Box<ExpireAction>,
+    ) -> Result<(), TimerError> {
+        self.insert_timer(Owner::User, id, expires_at, expire_action)
+    }
+
+    /// Returns a registry that shares the timers of this one but has ids of its own,
+    /// so a component can be handed one without being able to stop the timers of the rest.
+    pub fn child(self: &Arc<Self>) -> ChildRegistry {
+        ChildRegistry {
+            registry: Arc::clone(self),
+            namespace: self.next_child_id.fetch_add(1, atomic::Ordering::Relaxed),
+        }
+    }
+
+    fn insert_timer(
+        &self,
+        owner: Owner,
+        id: TimerId,
+        expires_at: Instant,
+        expire_action: Box<ExpireAction>,
+    ) -> Result<(), TimerError> {
+        let mut timers = self.timers.lock()?;
+
+        if let Some(max_timers) = self.max_timers
+            && timers.len() >= max_timers
+        {
+            return Err(TimerError::CapacityExceeded);
+        }
+
         timers.push(Reverse(Timer {
             id,
+            owner,
             expires_at,
-            expire_action: Box::new(expire_action),
+            expire_action,
         }));
+
+        self.wakeup.notify();
+
+        Ok(())
+    }
+
+    /// Returns a future that completes at `deadline`.
+    ///
+    /// Dropping the future before it completes stops the timer backing it.
+    /// Deadlines in the past complete right away.
+    ///
+    /// The future only holds a weak reference to the registry, so a forgotten future
+    /// doesn't keep it alive. It completes with `TimerError::RegistryDropped`
+    /// if the registry is dropped first.
+    pub fn sleep_until(self: &Arc<Self>, deadline: Instant) -> Sleep {
+        Sleep {
+            registry: Arc::downgrade(self),
+            deadline,
+            timer_id: None,
+            shared: Arc::new(Mutex::new(SleepShared {
+                expired: false,
+                waker: None,
+            })),
+        }
+    }
+
+    pub fn stop_timer(&self, id: TimerId) -> Result<(), TimerError> {
+        self.stop_owned_timer(Owner::User, id)
+    }
+
+    fn stop_owned_timer(&self, owner: Owner, id: TimerId) -> Result<(), TimerError> {
+        let mut timers = self.timers.lock()?;
+
+        let len_before = timers.len();
+        timers.retain(|Reverse(timer)| timer.owner != owner || timer.id != id);
+
+        if timers.len() == len_before {
+            return Err(TimerError::NotFound);
+        }
+
+        Ok(())
     }
 
-    pub fn stop_timer(&self, id: u64) {
-        let mut timers = self.timers.lock().unwrap();
-        timers.retain(|Reverse(timer)| timer.id != id);
+    /// Stops every timer in `ids` while holding the lock once.
+    ///
+    /// Returns the number of timers that were stopped. Ids without a pending timer are ignored.
+    /// Timers backing `Sleep` futures and timers started by a `ChildRegistry`
+    /// are never stopped, even if they use one of the ids.
+    pub fn stop_timers(&self, ids: &[TimerId]) -> Result<usize, TimerError> {
+        let ids: HashSet<TimerId> = ids.iter().copied().collect();
+
+        let mut timers = self.timers.lock()?;
+
+        let len_before = timers.len();
+        timers.retain(|Reverse(timer)| timer.owner != Owner::User || !ids.contains(&timer.id));
+
+        Ok(len_before - timers.len())
+    }
+
+    /// Gives back the memory the heap kept for timers that were stopped,
+    /// e.g. after stopping a big batch with `stop_timers`.
+    ///
+    /// Stopped timers are removed from the heap right away, so there are no
+    /// cancelled entries left to skip and the heap already only holds pending timers.
+    pub fn compact_cancelled(&self) -> Result<(), TimerError> {
+        self.timers.lock()?.shrink_to_fit();
+        Ok(())
+    }
+
+    /// Stops every timer for which `keep` returns false when called with its id
+    /// and the instant it expires at, dropping their actions without running them.
+    ///
+    /// The heap is rebuilt once, no matter how many timers are stopped.
+    /// Timers backing `Sleep` futures and timers started by a `ChildRegistry` are kept.
+    pub fn retain_timers(&self, keep: impl Fn(TimerId, Instant) -> bool) -> Result<(), TimerError> {
+        let mut timers = self.timers.lock()?;
+
+        timers.retain(|Reverse(timer)| {
+            timer.owner != Owner::User || keep(timer.id, timer.expires_at)
+        });
+
+        Ok(())
+    }
+
+    /// Returns the ids of the pending timers, ordered by expiration.
+    ///
+    /// Timers backing `Sleep` futures and timers started by a `ChildRegistry` are not included,
+    /// since their ids don't mean anything to the registry.
+    pub fn pending_ids(&self) -> Result<Vec<TimerId>, TimerError> {
+        let timers = self.timers.lock()?;
+
+        let mut pending: Vec<_> = timers
+            .iter()
+            .filter(|Reverse(timer)| timer.owner == Owner::User)
+            .map(|Reverse(timer)| (timer.expires_at, timer.id))
+            .collect();
+
+        pending.sort();
+
+        Ok(pending.into_iter().map(|(_, id)| id).collect())
+    }
+
+    /// Removes every pending timer from the registry without running it,
+    /// ordered by expiration, so they can be started in another registry.
+    ///
+    /// Timers backing `Sleep` futures and timers started by a `ChildRegistry`
+    /// stay in the registry.
+    pub fn drain_all(&self) -> Result<Vec<(TimerId, Instant, Box<ExpireAction>)>, TimerError> {
+        let mut timers = self.timers.lock()?;
+
+        let (mut drained, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut *timers)
+            .into_vec()
+            .into_iter()
+            .partition(|Reverse(timer)| timer.owner == Owner::User);
+
+        *timers = BinaryHeap::from(kept);
+
+        drained.sort_by_key(|Reverse(timer)| timer.expires_at);
+
+        Ok(drained
+            .into_iter()
+            .map(|Reverse(timer)| (timer.id, timer.expires_at, timer.expire_action))
+            .collect())
     }
 
-    pub fn expire_timers(&self, current_time: Instant) {
-        let mut timers = self.timers.lock().unwrap();
+    pub fn expire_timers(&self, current_time: Instant) -> Result<(), TimerError> {
+        let mut timers = self.timers.lock()?;
+        self.expire_due_timers(&mut timers, current_time);
+        Ok(())
+    }
 
+    /// Runs the actions of the timers in `timers` that expire by `current_time`.
+    fn expire_due_timers(&self, timers: &mut BinaryHeap<Reverse<Timer>>, current_time: Instant) {
         while let Some(Reverse(timer)) = timers.peek() && timer.expires_at <= current_time {
           let Reverse(timer) = timers.pop().unwrap();
-          (timer.expire_action)();
+          self.run_action(timer.expire_action);
+        }
+    }
+
+    /// Runs `action` on the worker pool if there is one, or on the calling thread.
+    fn run_action(&self, action: Box<ExpireAction>) {
+        match &self.workers {
+            None => action(),
+            Some(workers) => workers.run(action),
         }
     }
 }
 
 pub fn per_tick_bookkeeping(registry: Weak<Registry>) {
+    let wakeup = match registry.upgrade() {
+        None => {
+            return;
+        }
+        Some(registry) => {
+            registry.mark_started();
+            Arc::clone(&registry.wakeup)
+        }
+    };
+
     loop {
-        match registry.upgrade() {
+        let timeout = match registry.upgrade() {
             None => {
                 return;
             }
             Some(registry) => {
-                registry.expire_timers(Instant::now());
+                // A panicking action doesn't stop the thread, and the poison it leaves
+                // behind is cleared so the timers keep expiring.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    registry.expire_due_timers(
+                        &mut lock_clearing_poison(&registry.timers),
+                        registry.now(),
+                    )
+                }));
+                registry.time_until_next_check()
             }
-        }
+        };
 
-        std::thread::sleep(Duration::from_secs(1));
+        wakeup.wait(timeout);
     }
 }
 
+/// Locks `mutex`, clearing the poison left by an action that panicked while it was held.
+///
+/// Expired timers are removed before their actions run,
+/// so a panicking action doesn't leave the timers half updated.
+fn lock_clearing_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
 type ExpireAction = dyn FnOnce() + Send + Sync;
 
+/// Identifies a timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(pub u64);
+
+/// Who started a timer. Timers can only be stopped by whoever started them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Owner {
+    /// Started with `Registry::start_timer`.
+    User,
+    /// Started by a `Sleep` future.
+    Sleep,
+    /// Started with `ChildRegistry::start_timer` on the child with this namespace.
+    Child(u64),
+}
+
+/// Starts timers in the registry it was created from with `Registry::child`,
+/// with ids that only mean something to the child.
+///
+/// Timers started by a child can only be stopped by it, and timers started on the registry
+/// or another child can't be stopped by it, even if they use the same id.
+/// They expire with the other timers of the registry.
+pub struct ChildRegistry {
+    registry: Arc<Registry>,
+    namespace: u64,
+}
+
+impl ChildRegistry {
+    /// Like `Registry::start_timer`.
+    pub fn start_timer(
+        &self,
+        id: TimerId,
+        expires_at: Instant,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Result<Instant, TimerError> {
+        self.registry.insert_timer(
+            Owner::Child(self.namespace),
+            id,
+            expires_at,
+            Box::new(expire_action),
+        )?;
+        Ok(expires_at)
+    }
+
+    /// Stops the timer `id` started by this child.
+    pub fn stop_timer(&self, id: TimerId) -> Result<(), TimerError> {
+        self.registry
+            .stop_owned_timer(Owner::Child(self.namespace), id)
+    }
+}
+
 pub struct Timer {
-    id: u64,
+    id: TimerId,
+    owner: Owner,
     expires_at: Instant,
     expire_action: Box<ExpireAction>,
 }
@@ -93,24 +755,847 @@ impl Ord for Timer {
     }
 }
 
+/// Future returned by `Registry::sleep_until`.
+///
+/// Completes with `Ok(())` once the deadline passes. It completes with an error
+/// instead of never completing when its timer can't fire: `TimerError::Poisoned`
+/// if the registry lock was poisoned while it was being polled, and
+/// `TimerError::RegistryDropped` if the registry was dropped first.
+pub struct Sleep {
+    registry: Weak<Registry>,
+    deadline: Instant,
+    /// The timer started the first time the future was polled.
+    timer_id: Option<TimerId>,
+    shared: Arc<Mutex<SleepShared>>,
+}
+
+/// State shared between a `Sleep` and the timer backing it.
+struct SleepShared {
+    expired: bool,
+    /// The waker passed to the last call to `poll`.
+    waker: Option<Waker>,
+}
+
+/// Captured by the action of the timer backing a `Sleep`.
+/// Wakes the future up when it is dropped, after the action runs or
+/// without running it because the registry was dropped.
+struct SleepTimer(Arc<Mutex<SleepShared>>);
+
+impl Drop for SleepTimer {
+    fn drop(&mut self) {
+        let mut shared = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        // Lets the background thread notice the registry is gone.
+        self.wakeup.notify();
+    }
+}
+
+impl Future for Sleep {
+    type Output = Result<(), TimerError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        {
+            let mut shared = this.shared.lock()?;
+            if shared.expired {
+                return Poll::Ready(Ok(()));
+            }
+            shared.waker = Some(cx.waker().clone());
+        }
+
+        let Some(registry) = this.registry.upgrade() else {
+            return Poll::Ready(Err(TimerError::RegistryDropped));
+        };
+
+        if this.timer_id.is_none() {
+            if this.deadline <= registry.now() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let id = TimerId(
+                registry
+                    .next_sleep_id
+                    .fetch_add(1, atomic::Ordering::Relaxed),
+            );
+
+            let timer = SleepTimer(Arc::clone(&this.shared));
+            registry.timers.lock()?.push(Reverse(Timer {
+                id,
+                owner: Owner::Sleep,
+                expires_at: this.deadline,
+                expire_action: Box::new(move || {
+                    timer
+                        .0
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .expired = true;
+                    drop(timer);
+                }),
+            }));
+
+            registry.wakeup.notify();
+
+            this.timer_id = Some(id);
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // Nobody is waiting on the future anymore.
+        self.shared
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .waker = None;
+
+        if let Some(id) = self.timer_id
+            && let Some(registry) = self.registry.upgrade()
+        {
+            let mut timers = registry
+                .timers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            timers.retain(|Reverse(timer)| timer.owner != Owner::Sleep || timer.id != id);
+        }
+    }
+}
+
+/// Runs `future` to completion on the current thread, parking it while the future is pending.
+///
+/// Only meant for tests and programs that don't need an async runtime.
+#[cfg(any(test, feature = "block_on"))]
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    /// Unparks the thread that is blocked on the future.
+    struct ThreadWaker(std::thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = std::pin::pin!(future);
+
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        std::thread::park();
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize},
+        task::Wake,
+        time::Duration,
+    };
 
     use super::*;
 
     #[test]
     pub fn simple() {
+        let clock = Arc::new(ManualClock::new());
+        let registry = Registry::with_clock(Arc::clone(&clock));
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for (id, expires_in) in [(TimerId(0), 1), (TimerId(1), 3)] {
+            let fired = Arc::clone(&fired);
+            registry
+                .start_timer(
+                    id,
+                    clock.now() + Duration::from_secs(expires_in),
+                    move || {
+                        fired.lock().unwrap().push(id);
+                    },
+                )
+                .unwrap();
+        }
+
+        registry.advance(Duration::from_secs(3)).unwrap();
+
+        assert_eq!(vec![TimerId(0), TimerId(1)], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn rewinding_the_clock_does_not_delay_timers() {
+        let clock = Arc::new(ManualClock::new());
+        let registry = Registry::with_clock(Arc::clone(&clock));
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired_clone = Arc::clone(&fired);
+        let start = registry.now();
+        registry
+            .start_timer(TimerId(0), start + Duration::from_secs(2), move || {
+                fired_clone.store(true, atomic::Ordering::SeqCst);
+            })
+            .unwrap();
+
+        registry.advance(Duration::from_secs(1)).unwrap();
+        clock.rewind(Duration::from_secs(10));
+        assert_eq!(start + Duration::from_secs(1), registry.now());
+
+        registry.advance(Duration::from_secs(1)).unwrap();
+        assert!(fired.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stop_timer_by_id() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let now = Instant::now();
+        for id in [TimerId(0), TimerId(1)] {
+            let fired = Arc::clone(&fired);
+            registry
+                .start_timer(id, now + Duration::from_secs(1), move || {
+                    fired.lock().unwrap().push(id);
+                })
+                .unwrap();
+        }
+
+        registry.stop_timer(TimerId(0)).unwrap();
+        registry
+            .expire_timers(now + Duration::from_secs(1))
+            .unwrap();
+
+        assert_eq!(vec![TimerId(1)], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn stop_timers_stops_every_timer_in_one_call() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(AtomicU64::new(0));
+
+        let now = Instant::now();
+        for id in 0..1500 {
+            let fired = Arc::clone(&fired);
+            registry
+                .start_timer(TimerId(id), now + Duration::from_secs(1), move || {
+                    fired.fetch_add(1, atomic::Ordering::SeqCst);
+                })
+                .unwrap();
+        }
+
+        let ids: Vec<TimerId> = (500..1500).chain(2000..2010).map(TimerId).collect();
+        assert_eq!(Ok(1000), registry.stop_timers(&ids));
+
+        registry
+            .expire_timers(now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(500, fired.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn pending_ids_excludes_stopped_and_expired_timers() {
+        let registry = Registry::without_bookkeeping();
+
+        let now = Instant::now();
+        for (id, expires_in) in [(0, 3), (1, 1), (2, 2), (3, 4)] {
+            registry
+                .start_timer(TimerId(id), now + Duration::from_secs(expires_in), || {})
+                .unwrap();
+        }
+
+        registry.stop_timer(TimerId(2)).unwrap();
+        assert_eq!(
+            vec![TimerId(1), TimerId(0), TimerId(3)],
+            registry.pending_ids().unwrap()
+        );
+
+        registry
+            .expire_timers(now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            vec![TimerId(0), TimerId(3)],
+            registry.pending_ids().unwrap()
+        );
+    }
+
+    #[test]
+    fn retain_timers_stops_the_timers_that_do_not_match() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let now = Instant::now();
+        for id in 0..10 {
+            let fired = Arc::clone(&fired);
+            registry
+                .start_timer(TimerId(id), now + Duration::from_secs(id), move || {
+                    fired.lock().unwrap().push(id);
+                })
+                .unwrap();
+        }
+
+        registry
+            .retain_timers(|TimerId(id), _| id % 2 == 1)
+            .unwrap();
+        assert_eq!(
+            vec![TimerId(1), TimerId(3), TimerId(5), TimerId(7), TimerId(9)],
+            registry.pending_ids().unwrap()
+        );
+
+        registry
+            .retain_timers(|_, expires_at| expires_at <= now + Duration::from_secs(5))
+            .unwrap();
+
+        registry
+            .expire_timers(now + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(vec![1, 3, 5], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn drained_timers_fire_in_the_new_registry() {
+        let old_registry = Registry::without_bookkeeping();
+        let new_registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let now = Instant::now();
+        for (id, expires_in) in [(TimerId(0), 2), (TimerId(1), 1)] {
+            let fired = Arc::clone(&fired);
+            old_registry
+                .start_timer(id, now + Duration::from_secs(expires_in), move || {
+                    fired.lock().unwrap().push(id);
+                })
+                .unwrap();
+        }
+
+        let drained = old_registry.drain_all().unwrap();
+        assert_eq!(
+            vec![
+                (TimerId(1), now + Duration::from_secs(1)),
+                (TimerId(0), now + Duration::from_secs(2))
+            ],
+            drained
+                .iter()
+                .map(|(id, expires_at, _)| (*id, *expires_at))
+                .collect::<Vec<_>>()
+        );
+
+        for (id, expires_at, expire_action) in drained {
+            new_registry
+                .start_timer(id, expires_at, expire_action)
+                .unwrap();
+        }
+
+        old_registry
+            .expire_timers(now + Duration::from_secs(2))
+            .unwrap();
+        assert!(fired.lock().unwrap().is_empty());
+
+        new_registry
+            .expire_timers(now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(vec![TimerId(1)], *fired.lock().unwrap());
+
+        new_registry
+            .expire_timers(now + Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(vec![TimerId(1), TimerId(0)], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn reinserted_timers_fire_where_they_left_off() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let now = Instant::now();
+        for (id, expires_in) in [(TimerId(0), 1), (TimerId(1), 2), (TimerId(2), 3)] {
+            let fired = Arc::clone(&fired);
+            registry
+                .start_timer(id, now + Duration::from_secs(expires_in), move || {
+                    fired.lock().unwrap().push(id);
+                })
+                .unwrap();
+        }
+
+        registry
+            .expire_timers(now + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(Ok(()), registry.stop_timer(TimerId(2)));
+
+        for (id, expires_at, expire_action) in registry.drain_all().unwrap() {
+            assert_eq!(Ok(()), registry.reinsert(id, expires_at, expire_action));
+        }
+        assert_eq!(vec![TimerId(1)], registry.pending_ids().unwrap());
+
+        // A stopped id can be used again.
+        let fired_clone = Arc::clone(&fired);
+        registry
+            .reinsert(
+                TimerId(2),
+                now + Duration::from_secs(3),
+                Box::new(move || fired_clone.lock().unwrap().push(TimerId(2))),
+            )
+            .unwrap();
+
+        registry
+            .expire_timers(now + Duration::from_secs(3))
+            .unwrap();
+        assert_eq!(
+            vec![TimerId(0), TimerId(1), TimerId(2)],
+            *fired.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn start_timer_fails_when_registry_is_full() {
+        let mut registry = Registry::without_bookkeeping();
+        registry.max_timers = Some(1);
+
+        let now = Instant::now();
+        registry.start_timer(TimerId(0), now, || {}).unwrap();
+        assert_eq!(
+            Err(TimerError::CapacityExceeded),
+            registry.start_timer(TimerId(1), now, || {})
+        );
+
+        registry.expire_timers(now).unwrap();
+        assert_eq!(Ok(now), registry.start_timer(TimerId(1), now, || {}));
+    }
+
+    #[test]
+    fn start_timer_returns_the_deadline_it_scheduled() {
+        let registry = Registry::without_bookkeeping();
+
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(5);
+        assert_eq!(
+            Ok(deadline),
+            registry.start_timer(TimerId(0), deadline, || {})
+        );
+
+        // Deadlines in the past are kept, so they expire the next time the timers are checked.
+        let past = now - Duration::from_secs(5);
+        assert_eq!(Ok(past), registry.start_timer(TimerId(1), past, || {}));
+
+        registry.expire_timers(now).unwrap();
+        assert_eq!(vec![TimerId(0)], registry.pending_ids().unwrap());
+    }
+
+    #[test]
+    fn stop_timer_fails_when_timer_does_not_exist() {
+        let registry = Registry::without_bookkeeping();
+
+        assert_eq!(Err(TimerError::NotFound), registry.stop_timer(TimerId(0)));
+
+        registry
+            .start_timer(TimerId(0), Instant::now(), || {})
+            .unwrap();
+        assert_eq!(Ok(()), registry.stop_timer(TimerId(0)));
+        assert_eq!(Err(TimerError::NotFound), registry.stop_timer(TimerId(0)));
+    }
+
+    #[test]
+    fn registry_recovers_from_poisoned_lock() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+
+        let now = Instant::now();
+        registry
+            .start_timer(TimerId(0), now, || panic!("action panicked"))
+            .unwrap();
+
+        let registry_clone = Arc::clone(&registry);
+        let result = std::thread::spawn(move || registry_clone.expire_timers(now)).join();
+        assert!(result.is_err());
+
+        assert_eq!(
+            Err(TimerError::Poisoned),
+            registry.start_timer(TimerId(1), now, || {})
+        );
+        assert_eq!(Err(TimerError::Poisoned), registry.expire_timers(now));
+
+        registry.clear_poison();
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired_clone = Arc::clone(&fired);
+        registry
+            .start_timer(TimerId(1), now, move || {
+                fired_clone.store(true, atomic::Ordering::SeqCst);
+            })
+            .unwrap();
+        registry.expire_timers(now).unwrap();
+        assert!(fired.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn background_thread_survives_a_panicking_action() {
         let registry = Registry::new();
 
-        registry.start_timer(0, Instant::now() + Duration::from_secs(1), || {
-            println!("expired 1 sec");
-        });
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        // Both timers are started up front, so only the background thread
+        // can clear the poison the first one leaves behind.
+        let now = Instant::now();
+        registry
+            .start_timer(TimerId(1), now + Duration::from_millis(200), move || {
+                sender.lock().unwrap().send(()).unwrap();
+            })
+            .unwrap();
+        registry
+            .start_timer(TimerId(0), now, || panic!("action panicked"))
+            .unwrap();
+
+        receiver.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(!registry.timers.is_poisoned());
+    }
+
+    /// Counts how many times it was woken.
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn sleep_until_completes_at_deadline() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+
+        let counting_waker = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&counting_waker));
+        let mut cx = Context::from_waker(&waker);
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut sleep = registry.sleep_until(deadline);
+        assert_eq!(Poll::Pending, Pin::new(&mut sleep).poll(&mut cx));
+        assert_eq!(Poll::Pending, Pin::new(&mut sleep).poll(&mut cx));
+
+        // Sleep timers can't be stopped or drained by users.
+        assert_eq!(Err(TimerError::NotFound), registry.stop_timer(TimerId(0)));
+        assert!(registry.drain_all().unwrap().is_empty());
+
+        registry.expire_timers(deadline).unwrap();
+        assert_eq!(1, counting_waker.0.load(atomic::Ordering::SeqCst));
+        assert_eq!(Poll::Ready(Ok(())), Pin::new(&mut sleep).poll(&mut cx));
+    }
+
+    #[test]
+    fn sleep_until_past_deadline_completes_right_away() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+
+        let waker = Waker::from(Arc::new(CountingWaker(AtomicUsize::new(0))));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut sleep = registry.sleep_until(Instant::now());
+        assert_eq!(Poll::Ready(Ok(())), Pin::new(&mut sleep).poll(&mut cx));
+        assert!(registry.timers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dropping_sleep_stops_its_timer() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+
+        let counting_waker = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&counting_waker));
+        let mut cx = Context::from_waker(&waker);
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut sleep = registry.sleep_until(deadline);
+        assert_eq!(Poll::Pending, Pin::new(&mut sleep).poll(&mut cx));
+        assert_eq!(1, registry.timers.lock().unwrap().len());
+
+        drop(sleep);
+        assert!(registry.timers.lock().unwrap().is_empty());
+
+        registry.expire_timers(deadline).unwrap();
+        assert_eq!(0, counting_waker.0.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn wait_until_started_returns_before_the_first_tick() {
+        let clock = Arc::new(ManualClock::new());
+        let registry = Registry::with_clock(Arc::clone(&clock));
+
+        // Keeps the first tick from completing until the test is done.
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        registry
+            .start_timer(TimerId(0), clock.now(), move || {
+                let _ = released.lock().unwrap().recv();
+            })
+            .unwrap();
+
+        registry.wait_until_started();
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn timers_fire_close_to_their_deadline() {
+        let registry = Registry::with_resolution(Duration::from_millis(5));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        let deadline = Instant::now() + Duration::from_millis(120);
+        registry
+            .start_timer(TimerId(0), deadline, move || {
+                sender.lock().unwrap().send(Instant::now()).unwrap();
+            })
+            .unwrap();
+
+        let fired_at = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(fired_at >= deadline);
+        assert!(fired_at - deadline < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn background_thread_parks_until_a_timer_is_started() {
+        let registry = Registry::new();
+        registry.wait_until_started();
+
+        let parked_by = Instant::now() + Duration::from_secs(1);
+        while !registry.is_idle() {
+            assert!(Instant::now() < parked_by, "background thread did not park");
+            std::thread::yield_now();
+        }
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        registry
+            .start_timer(TimerId(0), deadline, move || {
+                sender.lock().unwrap().send(Instant::now()).unwrap();
+            })
+            .unwrap();
+
+        let fired_at = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(fired_at - deadline < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn worker_pool_runs_actions_in_parallel_off_the_tick_thread() {
+        let registry = Registry::with_worker_pool(4);
 
-        registry.start_timer(1, Instant::now() + Duration::from_secs(3), || {
-            println!("expired 3 sec");
+        // Every action waits for the others, so they only finish if they run at the same time.
+        let barrier = Arc::new(std::sync::Barrier::new(4));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Arc::new(Mutex::new(sender));
+
+        let now = Instant::now();
+        for id in 0..4 {
+            let barrier = Arc::clone(&barrier);
+            let sender = Arc::clone(&sender);
+            registry
+                .start_timer(TimerId(id), now, move || {
+                    barrier.wait();
+                    sender
+                        .lock()
+                        .unwrap()
+                        .send(std::thread::current().id())
+                        .unwrap();
+                })
+                .unwrap();
+        }
+        registry.expire_timers(now).unwrap();
+
+        let mut workers = HashSet::new();
+        for _ in 0..4 {
+            workers.insert(receiver.recv_timeout(Duration::from_secs(1)).unwrap());
+        }
+        assert_eq!(4, workers.len());
+        assert!(!workers.contains(&std::thread::current().id()));
+    }
+
+    #[test]
+    fn dropping_the_registry_runs_queued_actions_and_stops_the_workers() {
+        let registry = Registry::with_worker_pool(1);
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let now = Instant::now();
+        for id in 0..3 {
+            let fired = Arc::clone(&fired);
+            registry
+                .start_timer(TimerId(id), now, move || {
+                    std::thread::sleep(Duration::from_millis(20));
+                    fired.fetch_add(1, atomic::Ordering::SeqCst);
+                })
+                .unwrap();
+        }
+        registry.expire_timers(now).unwrap();
+
+        drop(registry);
+        assert_eq!(3, fired.load(atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn block_on_drives_sleep_until() {
+        let registry = Registry::new();
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(Ok(()), block_on(registry.sleep_until(deadline)));
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn advance_to_fires_every_due_timer_in_order() {
+        let clock = Arc::new(ManualClock::new());
+        let registry = Registry::with_clock(Arc::clone(&clock));
+        let start = registry.now();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for (id, offset_ms) in [(0, 700), (1, 50), (2, 3000), (3, 1200), (4, 50), (5, 5000)] {
+            let fired = Arc::clone(&fired);
+            registry
+                .start_timer(
+                    TimerId(id),
+                    start + Duration::from_millis(offset_ms),
+                    move || {
+                        fired.lock().unwrap().push(offset_ms);
+                    },
+                )
+                .unwrap();
+        }
+
+        registry.advance_to(start + Duration::from_secs(3)).unwrap();
+
+        assert_eq!(vec![50, 50, 700, 1200, 3000], *fired.lock().unwrap());
+        assert_eq!(start + Duration::from_secs(3), registry.now());
+
+        // Registries on the system clock expire what is due by `target` without waiting for it.
+        let registry = Registry::without_bookkeeping();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for (id, offset_ms) in [(0, 20), (1, 10), (2, 30)] {
+            let fired = Arc::clone(&fired);
+            registry
+                .start_timer(
+                    TimerId(id),
+                    start + Duration::from_millis(offset_ms),
+                    move || {
+                        fired.lock().unwrap().push(offset_ms);
+                    },
+                )
+                .unwrap();
+        }
+
+        registry
+            .advance_to(start + Duration::from_millis(25))
+            .unwrap();
+        assert_eq!(vec![10, 20], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn sleep_completes_with_an_error_when_the_registry_is_dropped() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+
+        let counting_waker = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&counting_waker));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut sleep = registry.sleep_until(Instant::now() + Duration::from_secs(60));
+        assert_eq!(Poll::Pending, Pin::new(&mut sleep).poll(&mut cx));
+
+        // The pending future doesn't keep the registry alive.
+        let weak_registry = Arc::downgrade(&registry);
+        drop(registry);
+        assert!(weak_registry.upgrade().is_none());
+
+        assert_eq!(1, counting_waker.0.load(atomic::Ordering::SeqCst));
+        assert_eq!(
+            Poll::Ready(Err(TimerError::RegistryDropped)),
+            Pin::new(&mut sleep).poll(&mut cx)
+        );
+    }
+
+    #[test]
+    fn block_on_returns_when_the_registry_is_dropped() {
+        let registry = Registry::new();
+        let sleep = registry.sleep_until(Instant::now() + Duration::from_secs(60));
+
+        let dropper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(registry);
         });
 
-        std::thread::sleep(Duration::from_secs(5));
+        assert_eq!(Err(TimerError::RegistryDropped), block_on(sleep));
+        dropper.join().unwrap();
+    }
+
+    #[test]
+    fn compact_cancelled_gives_back_the_memory_of_stopped_timers() {
+        let registry = Registry::without_bookkeeping();
+
+        let now = Instant::now();
+        let ids: Vec<TimerId> = (0..10_000).map(TimerId).collect();
+        for &id in &ids {
+            registry
+                .start_timer(id, now + Duration::from_secs(60), || {})
+                .unwrap();
+        }
+
+        assert_eq!(9_900, registry.stop_timers(&ids[100..]).unwrap());
+        assert_eq!(100, registry.timers.lock().unwrap().len());
+        assert!(registry.timers.lock().unwrap().capacity() >= 10_000);
+
+        registry.compact_cancelled().unwrap();
+
+        let timers = registry.timers.lock().unwrap();
+        assert_eq!(100, timers.len());
+        assert!(timers.capacity() < 10_000);
+    }
+
+    #[test]
+    fn children_only_stop_their_own_timers() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+        let first_child = registry.child();
+        let second_child = registry.child();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let expires_at = Instant::now() + Duration::from_secs(1);
+
+        // Every timer uses the same id.
+        let record = |name: &'static str| {
+            let fired = Arc::clone(&fired);
+            move || fired.lock().unwrap().push(name)
+        };
+        registry
+            .start_timer(TimerId(0), expires_at, record("parent"))
+            .unwrap();
+        first_child
+            .start_timer(TimerId(0), expires_at, record("first child"))
+            .unwrap();
+        second_child
+            .start_timer(TimerId(0), expires_at, record("second child"))
+            .unwrap();
+
+        first_child.stop_timer(TimerId(0)).unwrap();
+        assert_eq!(
+            Err(TimerError::NotFound),
+            first_child.stop_timer(TimerId(0))
+        );
+        assert_eq!(vec![TimerId(0)], registry.pending_ids().unwrap());
+
+        registry.expire_timers(expires_at).unwrap();
+
+        let mut fired = fired.lock().unwrap().clone();
+        fired.sort();
+        assert_eq!(vec!["parent", "second child"], fired);
     }
 }
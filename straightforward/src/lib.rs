@@ -1,26 +1,143 @@
 use std::{
-    ops::Sub,
-    sync::{Arc, Mutex, Weak},
+    collections::HashMap,
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak,
+    },
     time::Duration,
 };
 
 pub struct Registry {
+    /// How much time passes every time the timers are expired.
+    tick: Duration,
     timers: Mutex<Vec<Timer>>,
+    /// How long each throttled key must wait before it can fire again.
+    throttle_windows: Mutex<HashMap<u64, Duration>>,
+    /// The id of the next `Scope`.
+    next_scope_id: AtomicU64,
+    /// The number of timers that expired. Updated while the timers are locked.
+    fired_total: AtomicU64,
+    /// The number of pending timers that were stopped. Updated while the timers are locked.
+    cancelled_total: AtomicU64,
+    /// Wakes the background thread up when a timer is started while it is parked.
+    wakeup: Arc<Wakeup>,
+    /// Called when the last pending timer expires or is stopped.
+    on_empty: Mutex<Option<Arc<OnEmpty>>>,
+}
+
+/// Lets the background thread sleep while there is nothing to tick.
+struct Wakeup {
+    state: Mutex<WakeupState>,
+    changed: Condvar,
+}
+
+struct WakeupState {
+    /// Set when a timer is started, so timers started while the background thread
+    /// was not parked are not missed.
+    notified: bool,
+    /// Whether the background thread is waiting for a timer to be started.
+    parked: bool,
+}
+
+impl Wakeup {
+    fn notify(&self) {
+        self.state.lock().unwrap().notified = true;
+        self.changed.notify_all();
+    }
+
+    /// Blocks until `notify` is called.
+    fn park(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.parked = true;
+        let mut state = self
+            .changed
+            .wait_while(state, |state| !state.notified)
+            .unwrap();
+        state.parked = false;
+        state.notified = false;
+    }
+}
+
+/// A snapshot of what a registry has been doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of pending timers.
+    pub live: usize,
+    /// The number of timers that expired, counting every attempt of timers started
+    /// with `with_retries` and the timers collected with `tick_and_collect`.
+    pub fired_total: u64,
+    /// The number of pending timers that were stopped.
+    pub cancelled_total: u64,
 }
 
 impl Registry {
     pub fn new() -> Arc<Self> {
-        let registry = Arc::new(Self {
-            timers: Mutex::new(Vec::new()),
-        });
+        Self::with_tick(Duration::from_secs(1))
+    }
+
+    /// Creates a registry that is ticked every `tick` instead of every second,
+    /// so intervals that are not a whole number of seconds expire closer to on time.
+    pub fn with_tick(tick: Duration) -> Arc<Self> {
+        let registry = Arc::new(Self::without_bookkeeping_with_tick(tick));
         let registry_clone = Arc::downgrade(&registry);
         std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
         registry
     }
 
+    /// Creates a registry that is not ticked by a background thread.
+    /// Timers only expire when `expire_timers` is called.
+    pub fn without_bookkeeping() -> Self {
+        Self::without_bookkeeping_with_tick(Duration::from_secs(1))
+    }
+
+    /// Like `without_bookkeeping`, but every call to `expire_timers` advances the timers
+    /// by `tick` instead of a second, like the ticks of a registry created with `with_tick`.
+    pub fn without_bookkeeping_with_tick(tick: Duration) -> Self {
+        assert!(!tick.is_zero(), "ticks have to last some time");
+
+        Self {
+            tick,
+            timers: Mutex::new(Vec::new()),
+            throttle_windows: Mutex::new(HashMap::new()),
+            next_scope_id: AtomicU64::new(0),
+            fired_total: AtomicU64::new(0),
+            cancelled_total: AtomicU64::new(0),
+            wakeup: Arc::new(Wakeup {
+                state: Mutex::new(WakeupState {
+                    notified: false,
+                    parked: false,
+                }),
+                changed: Condvar::new(),
+            }),
+            on_empty: Mutex::new(None),
+        }
+    }
+
+    /// Calls `on_empty` every time the registry goes from having pending timers
+    /// to having none, because the last one expired or was stopped,
+    /// e.g. to tear down what the timers were for.
+    ///
+    /// Ticks on a registry that was already empty don't call it.
+    /// Replaces the callback set before.
+    pub fn set_on_empty(&self, on_empty: impl Fn() + Send + Sync + 'static) {
+        *lock_clearing_poison(&self.on_empty) = Some(Arc::new(on_empty));
+    }
+
+    /// Returns true if there are no pending timers.
+    pub fn is_empty(&self) -> bool {
+        lock_clearing_poison(&self.timers).is_empty()
+    }
+
+    /// Returns true if the background thread is parked until a timer is started.
+    pub fn is_idle(&self) -> bool {
+        self.wakeup.state.lock().unwrap().parked
+    }
+
     pub fn start_timer(
         &self,
-        id: u64,
+        id: TimerId,
         interval: Duration,
         expire_action: impl FnOnce() + Send + Sync + 'static,
     ) {
@@ -28,67 +145,437 @@ impl Registry {
         timers.push(Timer {
             id,
             interval,
-            expire_action: Box::new(expire_action),
+            action: Action::Once(Box::new(expire_action)),
+            scope: None,
+        });
+        self.wakeup.notify();
+    }
+
+    /// Starts a timer that has no action. It is only reported by `tick_and_collect`.
+    pub fn start_timer_without_action(&self, id: TimerId, interval: Duration) {
+        let mut timers = self.timers.lock().unwrap();
+        timers.push(Timer {
+            id,
+            interval,
+            action: Action::None,
+            scope: None,
+        });
+        self.wakeup.notify();
+    }
+
+    /// Creates a scope for timers whose actions borrow data from outside of it,
+    /// like `std::thread::scope` does for threads.
+    ///
+    /// Timers started in the scope that have not expired by the time `f` returns are stopped.
+    pub fn scope<'env, T>(
+        &self,
+        f: impl for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    ) -> T {
+        let scope = Scope {
+            registry: self,
+            id: self.next_scope_id.fetch_add(1, Ordering::Relaxed),
+            env: PhantomData,
+        };
+
+        f(&scope)
+    }
+
+    /// Runs `action` every `interval` until it returns true
+    /// or it has been retried `max_retries` times.
+    ///
+    /// The first attempt happens after `interval`, like any other timer.
+    pub fn with_retries(
+        &self,
+        id: TimerId,
+        interval: Duration,
+        max_retries: usize,
+        action: impl FnMut() -> bool + Send + Sync + 'static,
+    ) {
+        let mut timers = self.timers.lock().unwrap();
+        timers.push(Timer {
+            id,
+            interval,
+            action: Action::Retry {
+                action: Box::new(action),
+                interval,
+                retries_left: max_retries,
+            },
+            scope: None,
         });
+        self.wakeup.notify();
     }
 
-    pub fn stop_timer(&self, id: u64) {
+    /// Schedules `expire_action` to run after `interval` unless `debounce`
+    /// is called again with the same `key` before it runs.
+    /// Calling it again restarts the interval and replaces the action.
+    pub fn debounce(
+        &self,
+        key: TimerId,
+        interval: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) {
+        let mut timers = self.timers.lock().unwrap();
+
+        match timers.iter_mut().find(|timer| timer.id == key) {
+            None => timers.push(Timer {
+                id: key,
+                interval,
+                action: Action::Once(Box::new(expire_action)),
+                scope: None,
+            }),
+            Some(timer) => {
+                timer.interval = interval;
+                timer.action = Action::Once(Box::new(expire_action));
+                // The new action doesn't borrow from the scope the timer was started in.
+                timer.scope = None;
+            }
+        }
+        self.wakeup.notify();
+    }
+
+    /// Runs `action` right away unless it has already run for `key` in the
+    /// last `interval`, in which case `action` is dropped without running.
+    ///
+    /// Returns true if `action` ran.
+    pub fn throttle(&self, key: u64, interval: Duration, action: impl FnOnce()) -> bool {
+        {
+            let mut throttle_windows = self.throttle_windows.lock().unwrap();
+
+            if throttle_windows.contains_key(&key) {
+                return false;
+            }
+
+            throttle_windows.insert(key, interval);
+        }
+        self.wakeup.notify();
+
+        action();
+
+        true
+    }
+
+    /// Returns the id and the time left of every pending timer,
+    /// in the order they were started, so they can be started again with `import`.
+    pub fn export(&self) -> Vec<(TimerId, Duration)> {
+        let timers = self.timers.lock().unwrap();
+        timers
+            .iter()
+            .map(|timer| (timer.id, timer.interval))
+            .collect()
+    }
+
+    /// Starts a timer for every entry returned by `export`.
+    ///
+    /// Actions can't be exported, so `resolver` is asked for the action of each timer.
+    pub fn import(
+        &self,
+        entries: impl IntoIterator<Item = (TimerId, Duration)>,
+        resolver: impl Fn(TimerId) -> Box<ExpireAction>,
+    ) {
+        let mut timers = self.timers.lock().unwrap();
+
+        for (id, interval) in entries {
+            timers.push(Timer {
+                id,
+                interval,
+                action: Action::Once(resolver(id)),
+                scope: None,
+            });
+        }
+        self.wakeup.notify();
+    }
+
+    /// Changes the time left until the pending timer `id` expires to `new_interval`,
+    /// counting from now like the interval passed to `start_timer`.
+    ///
+    /// The timer keeps its place among the timers that expire on the same tick.
+    /// Returns false if there is no pending timer with that id.
+    pub fn modify_interval(&self, id: TimerId, new_interval: Duration) -> bool {
+        let mut timers = self.timers.lock().unwrap();
+
+        match timers.iter_mut().find(|timer| timer.id == id) {
+            None => false,
+            Some(timer) => {
+                timer.interval = new_interval;
+                true
+            }
+        }
+    }
+
+    pub fn stop_timer(&self, id: TimerId) {
         let mut timers = self.timers.lock().unwrap();
 
         for i in 0..timers.len() {
             if timers[i].id == id {
                 let _ = timers.remove(i);
-                break;
+                self.cancelled_total.fetch_add(1, Ordering::Relaxed);
+
+                if timers.is_empty() {
+                    drop(timers);
+                    self.emptied();
+                }
+                return;
             }
         }
     }
 
-    pub fn expire_timers(&self) {
-        let mut timers = self.timers.lock().unwrap();
+    /// Returns the number of pending timers and how many timers fired or were stopped so far.
+    pub fn stats(&self) -> Stats {
+        let timers = self.timers.lock().unwrap();
+
+        Stats {
+            live: timers.len(),
+            fired_total: self.fired_total.load(Ordering::Relaxed),
+            cancelled_total: self.cancelled_total.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Advances every timer by one tick and runs the actions of the timers that expired.
+    ///
+    /// Timers expire on the first tick at or after the end of their interval,
+    /// so intervals that are not a whole number of ticks are rounded up.
+    /// Timers that expire on the same tick run in the order they were started.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn expire_timers(&self) -> usize {
+        let mut timers = lock_clearing_poison(&self.timers);
 
-        let mut to_remove = vec![];
+        let was_empty = timers.is_empty();
+        let expired = self.advance(&mut timers);
 
-        let timers_iter = timers.iter_mut().enumerate();
-        for (i, timer) in timers_iter {
-            timer.interval = timer.interval.sub(Duration::from_secs(1));
-            if timer.interval.is_zero() {
-                to_remove.push(i);
+        let num_expired = expired.len();
+
+        for mut timer in expired.into_iter() {
+            match timer.action {
+                Action::None => {}
+                Action::Once(expire_action) => (expire_action)(),
+                Action::Retry {
+                    ref mut action,
+                    interval,
+                    ref mut retries_left,
+                } => {
+                    if !(action)() && *retries_left > 0 {
+                        *retries_left -= 1;
+                        timer.interval = interval;
+                        timers.push(timer);
+                    }
+                }
             }
         }
 
-        for i in to_remove.into_iter() {
-            let timer = timers.remove(i);
-            (timer.expire_action)();
+        self.fired_total
+            .fetch_add(num_expired as u64, Ordering::Relaxed);
+
+        let is_empty = timers.is_empty();
+        drop(timers);
+
+        if !was_empty && is_empty {
+            self.emptied();
         }
+
+        self.advance_throttle_windows();
+
+        num_expired
+    }
+
+    /// Advances every timer by one tick like `expire_timers`, but returns the ids of
+    /// the timers that expired instead of running their actions.
+    ///
+    /// The actions are dropped, and timers started with `with_retries` are not retried.
+    /// Meant for registries created with `without_bookkeeping`,
+    /// since the background thread would run the actions first.
+    pub fn tick_and_collect(&self) -> Vec<TimerId> {
+        let mut timers = lock_clearing_poison(&self.timers);
+
+        let was_empty = timers.is_empty();
+        let expired = self.advance(&mut timers);
+        // The actions are dropped while the timers are locked, like they run
+        // in `expire_timers`, so `Scope` can rely on it.
+        let ids: Vec<TimerId> = expired.into_iter().map(|timer| timer.id).collect();
+
+        self.fired_total
+            .fetch_add(ids.len() as u64, Ordering::Relaxed);
+
+        let is_empty = timers.is_empty();
+        drop(timers);
+
+        if !was_empty && is_empty {
+            self.emptied();
+        }
+
+        self.advance_throttle_windows();
+
+        ids
+    }
+
+    /// Advances every timer by one tick and takes out the timers that expired.
+    fn advance(&self, timers: &mut Vec<Timer>) -> Vec<Timer> {
+        for timer in timers.iter_mut() {
+            timer.interval = timer.interval.saturating_sub(self.tick);
+        }
+
+        let (expired, pending): (Vec<Timer>, Vec<Timer>) = std::mem::take(timers)
+            .into_iter()
+            .partition(|timer| timer.interval.is_zero());
+
+        *timers = pending;
+
+        expired
+    }
+
+    /// Calls the `on_empty` callback, without holding the timers lock
+    /// so it can start timers, or the callback's own lock so it can replace itself.
+    fn emptied(&self) {
+        let on_empty = lock_clearing_poison(&self.on_empty).clone();
+        if let Some(on_empty) = on_empty {
+            on_empty();
+        }
+    }
+
+    fn advance_throttle_windows(&self) {
+        let mut throttle_windows = lock_clearing_poison(&self.throttle_windows);
+        throttle_windows.retain(|_, window| {
+            *window = window.saturating_sub(self.tick);
+            !window.is_zero()
+        });
+    }
+}
+
+/// Locks `mutex`, clearing the poison left by a thread that panicked while holding it,
+/// e.g. while running an action.
+///
+/// Expired timers are taken out of the registry before their actions run,
+/// so a panicking action doesn't leave the timers half updated.
+fn lock_clearing_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        // Lets the background thread notice the registry is gone.
+        self.wakeup.notify();
     }
 }
 
 pub fn per_tick_bookkeeping(registry: Weak<Registry>) {
+    let (tick, wakeup) = match registry.upgrade() {
+        None => {
+            return;
+        }
+        Some(registry) => (registry.tick, Arc::clone(&registry.wakeup)),
+    };
+
     loop {
-        match registry.upgrade() {
+        let idle = match registry.upgrade() {
             None => {
                 return;
             }
             Some(registry) => {
-                registry.expire_timers();
+                // A panicking action only takes the timers that expired with it down.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| registry.expire_timers()));
+                // Throttle windows only shrink while the registry ticks.
+                registry.is_empty() && lock_clearing_poison(&registry.throttle_windows).is_empty()
             }
+        };
+
+        if idle {
+            wakeup.park();
         }
 
-        std::thread::sleep(Duration::from_secs(1));
+        std::thread::sleep(tick);
     }
 }
 
 type ExpireAction = dyn FnOnce() + Send + Sync;
+type RetryAction = dyn FnMut() -> bool + Send + Sync;
+type OnEmpty = dyn Fn() + Send + Sync;
+
+enum Action {
+    /// Started with `Registry::start_timer_without_action`.
+    None,
+    Once(Box<ExpireAction>),
+    /// Started with `Registry::with_retries`.
+    Retry {
+        /// Returns true when it doesn't need to run again.
+        action: Box<RetryAction>,
+        /// The time between attempts.
+        interval: Duration,
+        retries_left: usize,
+    },
+}
+
+/// Identifies a timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(pub u64);
 
 pub struct Timer {
-    id: u64,
+    id: TimerId,
     interval: Duration,
-    expire_action: Box<ExpireAction>,
+    action: Action,
+    /// The id of the `Scope` the timer was started in.
+    scope: Option<u64>,
+}
+
+/// Starts timers whose actions can borrow data that outlives the scope.
+/// Created with `Registry::scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+    registry: &'scope Registry,
+    id: u64,
+    /// Invariant over `'env`, like `std::thread::Scope`.
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Like `Registry::start_timer`, but `expire_action` only has to live as long as the scope.
+    pub fn start_timer(
+        &self,
+        id: TimerId,
+        interval: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'env,
+    ) {
+        let expire_action: Box<dyn FnOnce() + Send + Sync + 'env> = Box::new(expire_action);
+        // SAFETY: the timer is removed from the registry when the scope is dropped,
+        // before `'env` ends. Actions run while the timers are locked,
+        // so the action is not running once the timer is removed.
+        let expire_action: Box<ExpireAction> = unsafe { std::mem::transmute(expire_action) };
+
+        let mut timers = self.registry.timers.lock().unwrap();
+        timers.push(Timer {
+            id,
+            interval,
+            action: Action::Once(expire_action),
+            scope: Some(self.id),
+        });
+        self.registry.wakeup.notify();
+    }
+}
+
+impl Drop for Scope<'_, '_> {
+    fn drop(&mut self) {
+        // Runs even if an action panicked, or the actions would outlive what they borrow.
+        let mut timers = self
+            .registry
+            .timers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let len_before = timers.len();
+        timers.retain(|timer| timer.scope != Some(self.id));
+
+        self.registry
+            .cancelled_total
+            .fetch_add((len_before - timers.len()) as u64, Ordering::Relaxed);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
 
     use super::*;
 
@@ -96,14 +583,453 @@ mod tests {
     pub fn simple() {
         let registry = Registry::new();
 
-        registry.start_timer(0, Duration::from_secs(1), || {
+        registry.start_timer(TimerId(0), Duration::from_secs(1), || {
             println!("expired 1 sec");
         });
 
-        registry.start_timer(1, Duration::from_secs(3), || {
+        registry.start_timer(TimerId(1), Duration::from_secs(3), || {
             println!("expired 3 sec");
         });
 
         std::thread::sleep(Duration::from_secs(5));
     }
+
+    #[test]
+    fn debounce_fires_once_after_last_call() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..3 {
+            let fired = Arc::clone(&fired);
+            registry.debounce(TimerId(0), Duration::from_secs(3), move || {
+                fired.fetch_add(1, Ordering::SeqCst);
+            });
+            registry.expire_timers();
+        }
+
+        registry.expire_timers();
+        assert_eq!(0, fired.load(Ordering::SeqCst));
+
+        registry.expire_timers();
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+
+        for _ in 0..3 {
+            registry.expire_timers();
+        }
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn throttle_fires_at_most_once_per_interval() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = AtomicUsize::new(0);
+        let fire = || {
+            fired.fetch_add(1, Ordering::SeqCst);
+        };
+
+        assert!(registry.throttle(0, Duration::from_secs(2), fire));
+        assert!(!registry.throttle(0, Duration::from_secs(2), fire));
+        assert!(!registry.throttle(0, Duration::from_secs(2), fire));
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+
+        // Other keys are throttled independently.
+        assert!(registry.throttle(1, Duration::from_secs(2), || {}));
+
+        registry.expire_timers();
+        assert!(!registry.throttle(0, Duration::from_secs(2), fire));
+
+        registry.expire_timers();
+        assert!(registry.throttle(0, Duration::from_secs(2), fire));
+        assert_eq!(2, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stop_timer_by_id() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for id in [TimerId(0), TimerId(1)] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(id, Duration::from_secs(1), move || {
+                fired.lock().unwrap().push(id);
+            });
+        }
+
+        registry.stop_timer(TimerId(0));
+        registry.expire_timers();
+
+        assert_eq!(vec![TimerId(1)], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn timers_expiring_on_the_same_tick_fire_in_insertion_order() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for (id, interval) in [(0, 2), (1, 1), (2, 2), (3, 1), (4, 2)] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(TimerId(id), Duration::from_secs(interval), move || {
+                fired.lock().unwrap().push(id);
+            });
+        }
+
+        registry.expire_timers();
+        assert_eq!(vec![1, 3], *fired.lock().unwrap());
+
+        registry.expire_timers();
+        assert_eq!(vec![1, 3, 0, 2, 4], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn with_retries_stops_once_the_action_succeeds() {
+        let registry = Registry::without_bookkeeping();
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempts_clone = Arc::clone(&attempts);
+        registry.with_retries(TimerId(0), Duration::from_secs(1), 5, move || {
+            attempts_clone.fetch_add(1, Ordering::SeqCst) + 1 == 3
+        });
+
+        for expected_attempts in 1..=3 {
+            registry.expire_timers();
+            assert_eq!(expected_attempts, attempts.load(Ordering::SeqCst));
+        }
+
+        for _ in 0..5 {
+            registry.expire_timers();
+        }
+        assert_eq!(3, attempts.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn expire_timers_returns_the_number_of_actions_that_ran() {
+        let registry = Registry::without_bookkeeping();
+
+        for (id, interval) in [(0, 1), (1, 1), (2, 2)] {
+            registry.start_timer(TimerId(id), Duration::from_secs(interval), || {});
+        }
+
+        assert_eq!(2, registry.expire_timers());
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(0, registry.expire_timers());
+    }
+
+    #[test]
+    fn exported_timers_fire_after_import() {
+        let old_registry = Registry::without_bookkeeping();
+
+        for (id, interval) in [(0, 3), (1, 2)] {
+            old_registry.start_timer(TimerId(id), Duration::from_secs(interval), || {});
+        }
+        old_registry.expire_timers();
+
+        let exported = old_registry.export();
+        assert_eq!(
+            vec![
+                (TimerId(0), Duration::from_secs(2)),
+                (TimerId(1), Duration::from_secs(1))
+            ],
+            exported
+        );
+
+        let new_registry = Registry::without_bookkeeping();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        new_registry.import(exported, |id| {
+            let fired = Arc::clone(&fired);
+            Box::new(move || fired.lock().unwrap().push(id))
+        });
+
+        new_registry.expire_timers();
+        assert_eq!(vec![TimerId(1)], *fired.lock().unwrap());
+
+        new_registry.expire_timers();
+        assert_eq!(vec![TimerId(1), TimerId(0)], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn scoped_timers_can_borrow_from_the_stack() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Mutex::new(Vec::new());
+
+        registry.scope(|scope| {
+            for (id, interval) in [(0, 1), (1, 2), (2, 3)] {
+                let fired = &fired;
+                scope.start_timer(TimerId(id), Duration::from_secs(interval), move || {
+                    fired.lock().unwrap().push(id);
+                });
+            }
+
+            registry.expire_timers();
+            registry.expire_timers();
+        });
+
+        assert_eq!(vec![0, 1], *fired.lock().unwrap());
+
+        // The timer that had not expired was stopped when the scope ended.
+        assert!(registry.export().is_empty());
+        assert_eq!(0, registry.expire_timers());
+    }
+
+    #[test]
+    fn modify_interval_shortens_a_pending_timer() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(TimerId(0), Duration::from_secs(10), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        registry.expire_timers();
+
+        assert!(registry.modify_interval(TimerId(0), Duration::from_secs(2)));
+        assert!(!registry.modify_interval(TimerId(1), Duration::from_secs(2)));
+
+        registry.expire_timers();
+        assert_eq!(0, fired.load(Ordering::SeqCst));
+
+        registry.expire_timers();
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stats_count_live_fired_and_cancelled_timers() {
+        let registry = Registry::without_bookkeeping();
+
+        for (id, interval) in [(0, 1), (1, 1), (2, 2), (3, 3)] {
+            registry.start_timer(TimerId(id), Duration::from_secs(interval), || {});
+        }
+        assert_eq!(
+            Stats {
+                live: 4,
+                fired_total: 0,
+                cancelled_total: 0
+            },
+            registry.stats()
+        );
+
+        registry.stop_timer(TimerId(2));
+        // Not pending anymore.
+        registry.stop_timer(TimerId(2));
+        assert_eq!(
+            Stats {
+                live: 3,
+                fired_total: 0,
+                cancelled_total: 1
+            },
+            registry.stats()
+        );
+
+        registry.expire_timers();
+        assert_eq!(
+            Stats {
+                live: 1,
+                fired_total: 2,
+                cancelled_total: 1
+            },
+            registry.stats()
+        );
+
+        registry.scope(|scope| {
+            scope.start_timer(TimerId(4), Duration::from_secs(5), || {});
+        });
+        registry.expire_timers();
+        registry.expire_timers();
+        assert_eq!(
+            Stats {
+                live: 0,
+                fired_total: 3,
+                cancelled_total: 2
+            },
+            registry.stats()
+        );
+    }
+
+    fn wait_until_idle(registry: &Registry) {
+        let parked_by = Instant::now() + Duration::from_secs(2);
+        while !registry.is_idle() {
+            assert!(Instant::now() < parked_by, "background thread did not park");
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn background_thread_parks_while_there_are_no_timers() {
+        let registry = Registry::new();
+        assert!(registry.is_empty());
+        wait_until_idle(&registry);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+
+        registry.start_timer(TimerId(0), Duration::from_secs(1), move || {
+            sender.lock().unwrap().send(()).unwrap();
+        });
+        assert!(!registry.is_empty());
+
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+        assert!(registry.is_empty());
+        wait_until_idle(&registry);
+    }
+
+    #[test]
+    fn fractional_intervals_expire_on_the_first_tick_after_they_run_out() {
+        let registry = Registry::without_bookkeeping_with_tick(Duration::from_millis(500));
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(TimerId(0), Duration::from_secs_f64(1.5), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        registry.start_timer(TimerId(1), Duration::from_millis(1200), || {});
+
+        assert_eq!(0, registry.expire_timers());
+        assert_eq!(0, registry.expire_timers());
+        assert_eq!(2, registry.expire_timers());
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn sub_second_timers_expire_on_the_next_tick() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(TimerId(0), Duration::from_millis(500), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        registry.with_retries(TimerId(1), Duration::from_millis(500), 1, || false);
+
+        // Used to panic subtracting a second from half a second.
+        assert_eq!(2, registry.expire_timers());
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+
+        assert_eq!(1, registry.expire_timers());
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn background_thread_keeps_ticking_after_an_action_panics() {
+        let registry = Registry::with_tick(Duration::from_millis(10));
+
+        registry.start_timer(TimerId(0), Duration::from_millis(10), || {
+            panic!("action panicked while the timers were locked")
+        });
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sender = Arc::new(Mutex::new(sender));
+
+        let sender_clone = Arc::clone(&sender);
+        registry.start_timer(TimerId(1), Duration::from_millis(50), move || {
+            sender_clone.lock().unwrap().send(1).unwrap();
+        });
+        receiver.recv_timeout(Duration::from_secs(2)).unwrap();
+
+        // The poison was cleared, so timers can still be started.
+        registry.start_timer(TimerId(2), Duration::from_millis(10), move || {
+            sender.lock().unwrap().send(2).unwrap();
+        });
+        assert_eq!(2, receiver.recv_timeout(Duration::from_secs(2)).unwrap());
+    }
+
+    #[test]
+    fn tick_and_collect_returns_the_ids_of_expired_timers() {
+        let registry = Registry::without_bookkeeping();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+
+        registry.start_timer_without_action(TimerId(0), Duration::from_secs(1));
+        registry.start_timer(TimerId(1), Duration::from_secs(1), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        registry.start_timer_without_action(TimerId(2), Duration::from_secs(2));
+        registry.start_timer_without_action(TimerId(3), Duration::from_secs(1));
+
+        assert_eq!(
+            vec![TimerId(0), TimerId(1), TimerId(3)],
+            registry.tick_and_collect()
+        );
+        assert_eq!(0, fired.load(Ordering::SeqCst));
+        assert_eq!(
+            vec![(TimerId(2), Duration::from_secs(1))],
+            registry.export()
+        );
+
+        assert_eq!(vec![TimerId(2)], registry.tick_and_collect());
+        assert!(registry.is_empty());
+        assert_eq!(
+            Stats {
+                live: 0,
+                fired_total: 4,
+                cancelled_total: 0
+            },
+            registry.stats()
+        );
+    }
+
+    #[test]
+    fn on_empty_is_called_when_the_last_timer_goes_away() {
+        let registry = Registry::without_bookkeeping();
+
+        let emptied = Arc::new(AtomicUsize::new(0));
+        let emptied_clone = Arc::clone(&emptied);
+        registry.set_on_empty(move || {
+            emptied_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        registry.start_timer(TimerId(0), Duration::from_secs(5), || {});
+        registry.start_timer(TimerId(1), Duration::from_secs(5), || {});
+
+        registry.stop_timer(TimerId(0));
+        assert_eq!(0, emptied.load(Ordering::SeqCst));
+
+        registry.stop_timer(TimerId(1));
+        assert_eq!(1, emptied.load(Ordering::SeqCst));
+
+        // Nothing changes while the registry stays empty.
+        registry.stop_timer(TimerId(1));
+        registry.expire_timers();
+        assert_eq!(1, emptied.load(Ordering::SeqCst));
+
+        registry.start_timer(TimerId(2), Duration::from_secs(2), || {});
+        registry.expire_timers();
+        assert_eq!(1, emptied.load(Ordering::SeqCst));
+        registry.expire_timers();
+        assert_eq!(2, emptied.load(Ordering::SeqCst));
+
+        registry.start_timer_without_action(TimerId(3), Duration::from_secs(1));
+        assert_eq!(vec![TimerId(3)], registry.tick_and_collect());
+        assert_eq!(3, emptied.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn on_empty_can_replace_itself() {
+        let registry = Arc::new(Registry::without_bookkeeping());
+
+        let emptied = Arc::new(AtomicUsize::new(0));
+        let emptied_clone = Arc::clone(&emptied);
+        // Weak, so the callback doesn't keep the registry alive.
+        let registry_clone = Arc::downgrade(&registry);
+        registry.set_on_empty(move || {
+            emptied_clone.fetch_add(1, Ordering::SeqCst);
+            let emptied = Arc::clone(&emptied_clone);
+            registry_clone.upgrade().unwrap().set_on_empty(move || {
+                emptied.fetch_add(10, Ordering::SeqCst);
+            });
+        });
+
+        registry.start_timer(TimerId(0), Duration::from_secs(5), || {});
+        registry.stop_timer(TimerId(0));
+        assert_eq!(1, emptied.load(Ordering::SeqCst));
+
+        registry.start_timer(TimerId(0), Duration::from_secs(5), || {});
+        registry.stop_timer(TimerId(0));
+        assert_eq!(11, emptied.load(Ordering::SeqCst));
+    }
 }
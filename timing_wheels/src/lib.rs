@@ -2,147 +2,3060 @@
 #![feature(drain_filter)]
 
 use std::{
-    sync::{Arc, Mutex, Weak},
-    time::Duration,
+    collections::{BTreeMap, HashMap, HashSet},
+    panic::AssertUnwindSafe,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError, TrySendError},
+        Arc, Condvar, Mutex, MutexGuard, TryLockError,
+    },
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "background-thread")]
+use std::sync::Weak;
+
+mod ring_wheel;
+
+pub use ring_wheel::{RingWheel, RingWheelError};
+
+const DEFAULT_NUM_BUCKETS: usize = 100000;
+const DEFAULT_EXPIRE_CHUNK_SIZE: usize = 1024;
+const DEFAULT_NUM_COARSE_BUCKETS: usize = 64;
+/// How many expired timer ids a subscriber can fall behind by
+/// before new ids are dropped for it.
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
 pub struct Registry {
-    num_buckets: usize,
+    /// The maximum number of buckets the wheel may grow to when a timer
+    /// does not fit in the current horizon. `None` means the wheel never grows.
+    max_num_buckets: Option<usize>,
+    /// How many actions run between chances for other threads to use the registry
+    /// when a lot of timers expire on the same tick.
+    expire_chunk_size: usize,
+    /// How many threads share the actions of a tick.
+    expiry_parallelism: usize,
+    /// Which timer wins when a key is used twice.
+    key_policy: KeyPolicy,
+    /// When timers that expire after no time at all fire.
+    zero_delay_policy: ZeroDelayPolicy,
+    /// Held while timers are being expired so ticks are processed one at a time
+    /// even though actions run without holding the state lock.
+    expiring: Mutex<()>,
+    /// Receive the id of every timer that expires.
+    subscribers: Mutex<Vec<SyncSender<u64>>>,
+    /// Keeps actions that take too long from holding up the wheel.
+    watchdog: Option<Watchdog>,
+    /// Set once the background thread starts ticking.
+    started: Mutex<bool>,
+    started_changed: Condvar,
     state: Mutex<State>,
 }
 
+/// When a timer started with `Registry::start_timer_with_info` fired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FireInfo {
+    /// The tick the wheel was at when the timer fired.
+    pub fired_tick: u64,
+    /// The tick the timer was meant to fire at.
+    /// The timer fired `fired_tick - scheduled_tick` ticks late.
+    pub scheduled_tick: u64,
+    /// When the action started running.
+    pub wall_time: Instant,
+}
+
+/// What `Registry::expire_timers_at` did to catch up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpireReport {
+    /// The number of ticks the wheel advanced.
+    pub ticks_advanced: u64,
+    /// The number of actions that ran.
+    pub num_expired: usize,
+}
+
+impl ExpireReport {
+    /// Returns true if the wheel was behind and advanced more than one tick at once,
+    /// e.g. because the process was suspended, so some timers fired late.
+    pub fn coalesced(&self) -> bool {
+        self.ticks_advanced > 1
+    }
+}
+
+/// Runs actions on its own threads and stops waiting for each of them
+/// `max_callback_duration` after it started.
+struct Watchdog {
+    max_callback_duration: Duration,
+    /// Called with the id of every timer whose action took too long.
+    on_timeout: Box<dyn Fn(u64) + Send + Sync>,
+    /// Actions that were still running when the watchdog stopped waiting for them.
+    /// Periodic timers are sent back when their action finishes so they can be restarted.
+    in_flight: Mutex<Vec<(usize, Receiver<Option<Timer>>)>>,
+    /// Bounds the number of actions running at the same time.
+    limit: Option<Arc<InFlightLimit>>,
+    threads: WatchdogThreads,
+}
+
+/// An action the watchdog started.
+enum Watched {
+    /// The in-flight limit dropped the action. Holds the timer if it is periodic.
+    Dropped(Option<Timer>),
+    /// The action is running and sends its timer back once it finishes.
+    Running {
+        timer_id: usize,
+        deadline: Instant,
+        receiver: Receiver<Option<Timer>>,
+    },
+}
+
+impl Watchdog {
+    /// Starts the action of `timer`, which fired at `fired_tick`, on one of the watchdog's threads.
+    fn start(&self, timer: Timer, fired_tick: u64) -> Watched {
+        let timer_id = timer.id;
+
+        let slot = match &self.limit {
+            None => None,
+            Some(limit) => {
+                if !limit.acquire() {
+                    (limit.on_drop)(timer_id as u64);
+                    return Watched::Dropped(timer.interval.map(|_| timer));
+                }
+                Some(InFlightSlot(Arc::clone(limit)))
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        self.threads.run(Box::new(move || {
+            let timer = timer.run(fired_tick);
+            drop(slot);
+            let _ = sender.send(timer);
+        }));
+
+        Watched::Running {
+            timer_id,
+            deadline: Instant::now() + self.max_callback_duration,
+            receiver,
+        }
+    }
+
+    /// Waits for the action started with `start` until its deadline.
+    ///
+    /// Returns the timer if it is periodic and has to be restarted.
+    fn wait(&self, watched: Watched) -> Option<Timer> {
+        let (timer_id, deadline, receiver) = match watched {
+            Watched::Dropped(timer) => return timer,
+            Watched::Running {
+                timer_id,
+                deadline,
+                receiver,
+            } => (timer_id, deadline, receiver),
+        };
+
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(timer) => timer,
+            // The action panicked.
+            Err(RecvTimeoutError::Disconnected) => None,
+            Err(RecvTimeoutError::Timeout) => {
+                (self.on_timeout)(timer_id as u64);
+                self.in_flight.lock().unwrap().push((timer_id, receiver));
+                None
+            }
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The threads the watchdog runs actions on.
+///
+/// A thread is started when every thread is busy, and threads wait for the next action
+/// once theirs finishes, so ticks don't start a thread per action and an action that
+/// never finishes only holds up its own thread. The threads stop once the registry is dropped.
+struct WatchdogThreads {
+    sender: Sender<Job>,
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    /// The number of threads waiting for an action.
+    idle: Arc<Mutex<usize>>,
+}
+
+impl WatchdogThreads {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        Self {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            idle: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    fn run(&self, job: Job) {
+        {
+            // Every job sent has a thread set aside for it,
+            // so it never waits behind a slow action.
+            let mut idle = self.idle.lock().unwrap();
+            if *idle > 0 {
+                *idle -= 1;
+            } else {
+                let receiver = Arc::clone(&self.receiver);
+                let idle = Arc::clone(&self.idle);
+                std::thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+
+                    match job {
+                        Ok(job) => {
+                            // A panicking action doesn't take the thread down with it.
+                            let _ = std::panic::catch_unwind(AssertUnwindSafe(job));
+                            *idle.lock().unwrap() += 1;
+                        }
+                        Err(mpsc::RecvError) => return,
+                    }
+                });
+            }
+        }
+
+        // The receiver lives as long as the threads.
+        let _ = self.sender.send(job);
+    }
+}
+
+/// Which timer is kept when `Registry::start_timer_keyed` is called with a key
+/// that already has a pending timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPolicy {
+    /// Keep the timer that expires first and drop the other one.
+    /// The pending timer is kept if both expire on the same tick.
+    KeepEarliest,
+    /// Stop the pending timer and start the new one.
+    Replace,
+}
+
+/// When a timer started with `Registry::start_timer` to expire after no time at all fires.
+///
+/// Applies to `start_timer`, `start_timer_with_info`, `start_timer_with_state`
+/// and `schedule` with `When::In`. Keyed timers always wait for the next tick,
+/// since they have to be in the wheel for their key to be taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroDelayPolicy {
+    /// Fire on the next tick, like any other timer that expires in less than a tick.
+    NextTick,
+    /// Run the action right away, on the thread that starts the timer,
+    /// without adding it to the wheel. The watchdog and the in-flight limit
+    /// still apply.
+    Immediate,
+}
+
+/// What to do with an expired timer when the maximum number of actions are already running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InFlightPolicy {
+    /// Wait up to the given time for an action to finish before dropping the action.
+    Block(Duration),
+    /// Drop the action right away.
+    Drop,
+}
+
+struct InFlightLimit {
+    max_in_flight: usize,
+    policy: InFlightPolicy,
+    /// Called with the id of every timer whose action was dropped.
+    on_drop: Box<dyn Fn(u64) + Send + Sync>,
+    /// The number of actions running.
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl InFlightLimit {
+    /// Reserves a slot for an action, applying the policy if there are none left.
+    ///
+    /// Returns false if the action has to be dropped.
+    fn acquire(&self) -> bool {
+        let running = self.running.lock().unwrap();
+
+        let mut running = match self.policy {
+            InFlightPolicy::Drop => running,
+            InFlightPolicy::Block(timeout) => {
+                self.finished
+                    .wait_timeout_while(running, timeout, |running| *running >= self.max_in_flight)
+                    .unwrap()
+                    .0
+            }
+        };
+
+        if *running >= self.max_in_flight {
+            return false;
+        }
+
+        *running += 1;
+        true
+    }
+}
+
+/// A slot reserved with `InFlightLimit::acquire`, freed when dropped
+/// so it is freed even if the action panics.
+struct InFlightSlot(Arc<InFlightLimit>);
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+/// When a timer started with `Registry::schedule` expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum When {
+    /// After the given time, like `Registry::start_timer`,
+    /// including for `Duration::ZERO` and the `ZeroDelayPolicy`.
+    In(Duration),
+    /// At the tick the given instant falls in, counting from when the wheel was anchored.
+    /// Instants the wheel already reached expire on the next tick.
+    At(Instant),
+    /// When the wheel reaches the given tick, like `Registry::start_timer_at_tick`.
+    AtTick(u64),
+}
+
+/// The reason a timer could not be scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// The wheel already reached the tick the timer was meant to expire at.
+    PastTick,
+}
+
+impl std::fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduleError::PastTick => write!(f, "tick has already been reached"),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
 pub struct State {
     next_timer_id: usize,
+    /// The number of ticks since the registry was created.
     current_time: u64,
+    /// How much time a tick covers.
+    tick_duration: Duration,
+    /// The tick the wheel was meant to be at at `anchored_at`.
+    /// Moves every time the tick duration changes, so ticks that already passed
+    /// keep the duration they had.
+    anchor_tick: u64,
+    anchored_at: Instant,
     timers: Vec<Vec<Timer>>,
+    /// How many timers every bucket of `timers` has room for when it is created.
+    bucket_capacity: usize,
+    /// A second wheel where every bucket holds the timers that expire during a whole turn
+    /// of `timers`. Timers that expire more than a turn away wait here and move to `timers`
+    /// when the turn they expire in starts.
+    ///
+    /// Empty until the first timer that expires more than a turn away is started,
+    /// so registries whose timers fit in the wheel never allocate it.
+    coarse_timers: Vec<Vec<Timer>>,
+    /// The number of buckets `coarse_timers` gets when it is needed.
+    num_coarse_buckets: usize,
+    /// Periodic timers that were stopped while their bucket was being expired.
+    /// They are not started again.
+    stopped_while_expiring: HashSet<usize>,
+    /// The tick every one-shot timer that ran early with `Registry::fire_now` was
+    /// meant to expire at, by timer id, until the wheel gets there.
+    /// Their handles are not stale before then.
+    fired_early: HashMap<usize, u64>,
+    /// The pending timer started with `Registry::start_timer_keyed` for every key.
+    keys: HashMap<u64, TimerHandle>,
 }
 
-impl Registry {
-    pub fn new() -> Arc<Self> {
-        let num_buckets = 100000;
+impl State {
+    fn num_buckets(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Returns the number of whole ticks in `duration`.
+    fn ticks_in(&self, duration: Duration) -> u64 {
+        u64::try_from(duration.as_nanos() / self.tick_duration.as_nanos()).unwrap_or(u64::MAX)
+    }
+
+    /// Returns the tick the wheel is meant to be at at `now`.
+    fn tick_at(&self, now: Instant) -> u64 {
+        self.anchor_tick
+            .saturating_add(self.ticks_in(now.saturating_duration_since(self.anchored_at)))
+    }
+
+    fn bucket_position(&self, expires_at: u64) -> usize {
+        expires_at as usize % self.num_buckets()
+    }
+
+    /// Returns the turn of the wheel `tick` is in.
+    fn turn(&self, tick: u64) -> u64 {
+        tick / self.num_buckets() as u64
+    }
+
+    fn coarse_bucket_position(&self, expires_at: u64) -> usize {
+        self.turn(expires_at) as usize % self.num_coarse_buckets
+    }
+
+    /// Adds `timer` to the wheel if it expires within a turn of the wheel,
+    /// otherwise to the coarse wheel.
+    fn place(&mut self, timer: Timer) {
+        if timer.expires_at.saturating_sub(self.current_time) <= self.num_buckets() as u64 {
+            let bucket_position = self.bucket_position(timer.expires_at);
+            self.timers[bucket_position].push(timer);
+        } else {
+            if self.coarse_timers.is_empty() {
+                self.coarse_timers
+                    .resize_with(self.num_coarse_buckets, Vec::new);
+            }
+
+            let coarse_bucket_position = self.coarse_bucket_position(timer.expires_at);
+            self.coarse_timers[coarse_bucket_position].push(timer);
+        }
+    }
+
+    /// Moves the timers that expire during the turn of the wheel that starts
+    /// at the current tick from the coarse wheel to the wheel.
+    fn cascade(&mut self) {
+        if self.bucket_position(self.current_time) != 0 || self.coarse_timers.is_empty() {
+            return;
+        }
+
+        let turn = self.turn(self.current_time);
+        let coarse_bucket_position = self.coarse_bucket_position(self.current_time);
+
+        let (cascaded, later): (Vec<Timer>, Vec<Timer>) =
+            std::mem::take(&mut self.coarse_timers[coarse_bucket_position])
+                .into_iter()
+                .partition(|timer| self.turn(timer.expires_at) == turn);
+
+        self.coarse_timers[coarse_bucket_position] = later;
+
+        let mut buckets: BTreeMap<usize, Vec<Timer>> = BTreeMap::new();
+        for timer in cascaded {
+            buckets
+                .entry(self.bucket_position(timer.expires_at))
+                .or_default()
+                .push(timer);
+        }
+
+        // Cascaded timers were started before any timer that went straight to the wheel
+        // and expires on the same tick, so they go first.
+        for (bucket_position, mut bucket) in buckets {
+            bucket.append(&mut self.timers[bucket_position]);
+            self.timers[bucket_position] = bucket;
+        }
+    }
+
+    /// Returns the tick at which the timer that `timer_handle` points to expires next.
+    fn next_expiration(&self, timer_handle: &TimerHandle) -> u64 {
+        match timer_handle.interval {
+            Some(interval) if timer_handle.expires_at <= self.current_time => {
+                let elapsed = self.current_time - timer_handle.expires_at;
+                timer_handle.expires_at + (elapsed / interval + 1) * interval
+            }
+            _ => timer_handle.expires_at,
+        }
+    }
+
+    /// Removes the timer `timer_handle` points to from the wheel it is in, if it is pending.
+    fn take(&mut self, timer_handle: &TimerHandle) -> Option<Timer> {
+        let expires_at = self.next_expiration(timer_handle);
+        let bucket_position = self.bucket_position(expires_at);
+        let coarse_bucket_position = self.coarse_bucket_position(expires_at);
+
+        let timer =
+            take_timer(&mut self.timers[bucket_position], timer_handle.timer_id).or_else(|| {
+                take_timer(
+                    self.coarse_timers.get_mut(coarse_bucket_position)?,
+                    timer_handle.timer_id,
+                )
+            })?;
+
+        if let Some(key) = timer.key {
+            self.keys.remove(&key);
+        }
+
+        Some(timer)
+    }
+
+    /// Grows the wheel to `num_buckets` buckets and moves every pending timer
+    /// to the bucket it belongs to in the bigger wheel.
+    fn extend_horizon(&mut self, num_buckets: usize) {
+        if num_buckets <= self.num_buckets() {
+            return;
+        }
+
+        let mut timers = Vec::new();
+        timers.resize_with(num_buckets, || Vec::with_capacity(self.bucket_capacity));
+        let old_timers = std::mem::replace(&mut self.timers, timers);
+
+        let mut coarse_timers = Vec::new();
+        coarse_timers.resize_with(self.coarse_timers.len(), Vec::new);
+        let old_coarse_timers = std::mem::replace(&mut self.coarse_timers, coarse_timers);
+
+        // Timers in the coarse wheel were started before the timers in the wheel
+        // that expire on the same tick, so they are placed first to keep them in order.
+        for timer in old_coarse_timers
+            .into_iter()
+            .flatten()
+            .chain(old_timers.into_iter().flatten())
+        {
+            self.place(timer);
+        }
+    }
+}
+
+pub struct Builder {
+    num_buckets: usize,
+    bucket_capacity: usize,
+    max_num_buckets: Option<usize>,
+    num_coarse_buckets: usize,
+    expire_chunk_size: usize,
+    expiry_parallelism: usize,
+    key_policy: KeyPolicy,
+    zero_delay_policy: ZeroDelayPolicy,
+    watchdog: Option<Watchdog>,
+    in_flight_limit: Option<InFlightLimit>,
+}
+
+impl Builder {
+    /// The number of buckets the wheel starts with.
+    pub fn num_buckets(mut self, num_buckets: usize) -> Self {
+        self.num_buckets = num_buckets;
+        self
+    }
+
+    /// Makes room for `per_bucket` timers in every bucket of the wheel up front,
+    /// so the first timers started in a bucket don't have to allocate.
+    /// Buckets added when the wheel grows get the same room.
+    pub fn bucket_capacity(mut self, per_bucket: usize) -> Self {
+        self.bucket_capacity = per_bucket;
+        self
+    }
+
+    /// Allows the wheel to grow up to `max_num_buckets` buckets when a timer
+    /// that does not fit in the current horizon is started.
+    pub fn max_num_buckets(mut self, max_num_buckets: usize) -> Self {
+        self.max_num_buckets = Some(max_num_buckets);
+        self
+    }
+
+    /// The number of buckets in the coarse wheel that holds timers
+    /// that expire more than a turn of the wheel away.
+    pub fn num_coarse_buckets(mut self, num_coarse_buckets: usize) -> Self {
+        self.num_coarse_buckets = num_coarse_buckets;
+        self
+    }
+
+    /// How many actions run before yielding to other threads
+    /// when a lot of timers expire on the same tick.
+    pub fn expire_chunk_size(mut self, expire_chunk_size: usize) -> Self {
+        self.expire_chunk_size = expire_chunk_size;
+        self
+    }
+
+    /// Splits the timers that expire on a tick between `num_threads` threads
+    /// that run their share of the actions at the same time.
+    ///
+    /// With more than one thread, timers that expire on the same tick don't run
+    /// in the order they were started anymore and `expire_chunk_size` is ignored.
+    /// The threads are spawned for every tick with more than one timer.
+    pub fn expiry_parallelism(mut self, num_threads: usize) -> Self {
+        self.expiry_parallelism = num_threads;
+        self
+    }
+
+    /// Which timer wins when `start_timer_keyed` is called with a key that
+    /// already has a pending timer. `KeyPolicy::KeepEarliest` by default.
+    pub fn key_policy(mut self, key_policy: KeyPolicy) -> Self {
+        self.key_policy = key_policy;
+        self
+    }
+
+    /// When timers started to expire after `Duration::ZERO` fire.
+    /// `ZeroDelayPolicy::NextTick` by default.
+    pub fn zero_delay_policy(mut self, zero_delay_policy: ZeroDelayPolicy) -> Self {
+        self.zero_delay_policy = zero_delay_policy;
+        self
+    }
+
+    /// Runs actions on threads of their own and calls `on_timeout` with the timer id
+    /// of actions that take longer than `max_callback_duration`.
+    /// The wheel moves on to the other timers without waiting for them to finish.
+    ///
+    /// The actions of a tick all start before the watchdog waits for any of them,
+    /// so they run at the same time and a slow action doesn't hold up the others.
+    /// Threads are reused once their action finishes.
+    ///
+    /// Periodic timers whose action took too long are restarted once it finishes,
+    /// skipping the expirations they missed.
+    pub fn watchdog(
+        mut self,
+        max_callback_duration: Duration,
+        on_timeout: impl Fn(u64) + Send + Sync + 'static,
+    ) -> Self {
+        self.watchdog = Some(Watchdog {
+            max_callback_duration,
+            on_timeout: Box::new(on_timeout),
+            in_flight: Mutex::new(Vec::new()),
+            limit: None,
+            threads: WatchdogThreads::new(),
+        });
+        self
+    }
+
+    /// Keeps the watchdog from running more than `max_in_flight` actions at the same time,
+    /// so a burst of slow actions can't start an unbounded number of threads.
+    /// Expired timers that find every slot taken are handled according to `policy`
+    /// and `on_drop` is called with the id of the timers whose action was dropped.
+    ///
+    /// Periodic timers whose action was dropped are restarted as if it had run.
+    pub fn max_in_flight(
+        mut self,
+        max_in_flight: usize,
+        policy: InFlightPolicy,
+        on_drop: impl Fn(u64) + Send + Sync + 'static,
+    ) -> Self {
+        self.in_flight_limit = Some(InFlightLimit {
+            max_in_flight,
+            policy,
+            on_drop: Box::new(on_drop),
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        });
+        self
+    }
+
+    /// Builds a registry that is ticked by a background thread,
+    /// unless the `background-thread` feature is disabled.
+    pub fn build(self) -> Arc<Registry> {
+        let registry = Arc::new(self.build_registry());
+
+        #[cfg(feature = "background-thread")]
+        {
+            let registry_clone = Arc::downgrade(&registry);
+            std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
+        }
+
+        // There is no thread to wait for.
+        #[cfg(not(feature = "background-thread"))]
+        registry.mark_started();
+
+        registry
+    }
+
+    /// Builds a registry that is not ticked by a background thread.
+    /// Timers only expire when `expire_timers` is called.
+    pub fn build_registry(self) -> Registry {
+        assert!(self.num_buckets > 0, "the wheel needs at least one bucket");
+        assert!(
+            self.num_coarse_buckets > 0,
+            "the coarse wheel needs at least one bucket"
+        );
+        assert!(
+            self.expire_chunk_size > 0,
+            "chunks need at least one action"
+        );
+        assert!(
+            self.expiry_parallelism > 0,
+            "actions need at least one thread to run on"
+        );
+
+        let mut watchdog = self.watchdog;
+        if let Some(limit) = self.in_flight_limit {
+            assert!(limit.max_in_flight > 0, "at least one action has to run");
+            let watchdog = watchdog
+                .as_mut()
+                .expect("only actions run by the watchdog can be limited");
+            watchdog.limit = Some(Arc::new(limit));
+        }
 
         let mut timers = Vec::new();
-        timers.resize_with(num_buckets, Vec::new);
+        timers.resize_with(self.num_buckets, || {
+            Vec::with_capacity(self.bucket_capacity)
+        });
 
-        let registry = Arc::new(Self {
-            num_buckets,
+        Registry {
+            max_num_buckets: self.max_num_buckets,
+            expire_chunk_size: self.expire_chunk_size,
+            expiry_parallelism: self.expiry_parallelism,
+            key_policy: self.key_policy,
+            zero_delay_policy: self.zero_delay_policy,
+            expiring: Mutex::new(()),
+            subscribers: Mutex::new(Vec::new()),
+            watchdog,
+            started: Mutex::new(false),
+            started_changed: Condvar::new(),
             state: Mutex::new(State {
                 next_timer_id: 0,
                 current_time: 0,
+                tick_duration: Duration::from_secs(1),
+                bucket_capacity: self.bucket_capacity,
+                anchor_tick: 0,
+                anchored_at: Instant::now(),
                 timers,
+                coarse_timers: Vec::new(),
+                num_coarse_buckets: self.num_coarse_buckets,
+                stopped_while_expiring: HashSet::new(),
+                fired_early: HashMap::new(),
+                keys: HashMap::new(),
             }),
-        });
-        let registry_clone = Arc::downgrade(&registry);
-        std::thread::spawn(move || per_tick_bookkeeping(registry_clone));
-        registry
+        }
     }
+}
 
-    pub fn start_timer(
-        &self,
-        expires_in: Duration,
-        expire_action: impl FnOnce() + Send + Sync + 'static,
-    ) -> TimerHandle {
-        let mut state = self.state.lock().unwrap();
+impl Registry {
+    pub fn new() -> Arc<Self> {
+        Self::builder().build()
+    }
 
-        let timer_id = state.next_timer_id;
-        state.next_timer_id = state.next_timer_id.saturating_add(1);
+    pub fn builder() -> Builder {
+        Builder {
+            num_buckets: DEFAULT_NUM_BUCKETS,
+            bucket_capacity: 0,
+            max_num_buckets: None,
+            num_coarse_buckets: DEFAULT_NUM_COARSE_BUCKETS,
+            expire_chunk_size: DEFAULT_EXPIRE_CHUNK_SIZE,
+            expiry_parallelism: 1,
+            key_policy: KeyPolicy::KeepEarliest,
+            zero_delay_policy: ZeroDelayPolicy::NextTick,
+            watchdog: None,
+            in_flight_limit: None,
+        }
+    }
 
-        let expires_in_as_seconds = expires_in.as_secs();
-        // TODO: if the number of seconds that the time should wait before expiring
-        // is greater than the number of buckets, the timer should go to a overflow list.
-        let bucket_position =
-            (state.current_time + expires_in_as_seconds) as usize % self.num_buckets;
+    /// Blocks until the background thread that expires timers has started ticking.
+    /// Returns right away if the `background-thread` feature is disabled.
+    pub fn wait_until_started(&self) {
+        let started = self.started.lock().unwrap();
+        let _started = self
+            .started_changed
+            .wait_while(started, |started| !*started)
+            .unwrap();
+    }
 
-        state.timers[bucket_position].push(Timer {
-            id: timer_id,
-            expire_action: Box::new(expire_action),
-        });
+    fn mark_started(&self) {
+        *self.started.lock().unwrap() = true;
+        self.started_changed.notify_all();
+    }
 
-        TimerHandle {
-            bucket_position,
-            timer_id,
-        }
+    /// Returns the number of buckets in the wheel.
+    pub fn num_buckets(&self) -> usize {
+        self.state.lock().unwrap().num_buckets()
     }
 
-    pub fn stop_timer(&self, timer_handle: &TimerHandle) {
+    /// Returns how far in the future a timer can expire and still go straight to the wheel.
+    /// Timers that expire later wait in the coarse wheel.
+    pub fn horizon(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        state.tick_duration * state.num_buckets() as u32
+    }
+
+    /// Returns how much time a tick covers. Ticks last a second until `set_tick_duration` is called.
+    pub fn tick_duration(&self) -> Duration {
+        self.state.lock().unwrap().tick_duration
+    }
+
+    /// Changes how much time a tick covers, e.g. to tick less often while the system is idle.
+    ///
+    /// Durations given to the registry from now on are converted to ticks of the new length,
+    /// and the background thread sleeps for the new length between ticks.
+    /// Pending timers keep the number of ticks they had left, so they expire
+    /// sooner or later than they would have in wall-clock time.
+    pub fn set_tick_duration(&self, tick_duration: Duration) {
+        assert!(!tick_duration.is_zero(), "ticks have to last some time");
+
         let mut state = self.state.lock().unwrap();
 
-        // TODO: this is slow but that's okay for now.
-        let index = state.timers[timer_handle.bucket_position]
+        let now = Instant::now();
+        state.anchor_tick = state.tick_at(now);
+        state.anchored_at = now;
+        state.tick_duration = tick_duration;
+    }
+
+    /// Returns the number of timers waiting in the coarse wheel because they expire
+    /// beyond the horizon.
+    pub fn overflow_len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.coarse_timers.iter().map(Vec::len).sum()
+    }
+
+    /// Returns the number of ticks until the next timer expires, or `None` if there are no timers,
+    /// so whatever drives the wheel can sleep until then.
+    ///
+    /// Scans the buckets forward from the current tick, and the coarse wheel if they are empty.
+    pub fn ticks_until_next(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+
+        // Every timer in the wheel expires within a turn, so the first bucket
+        // with a timer in it holds the next one.
+        let in_wheel = (1..=state.num_buckets() as u64).find(|&ticks| {
+            !state.timers[state.bucket_position(state.current_time + ticks)].is_empty()
+        });
+
+        let in_coarse_wheel = state
+            .coarse_timers
             .iter()
-            .position(|timer| timer.id == timer_handle.timer_id);
+            .flatten()
+            .map(|timer| timer.expires_at.saturating_sub(state.current_time))
+            .min();
 
-        if let Some(index) = index {
-            state.timers[timer_handle.bucket_position].remove(index);
-        }
+        in_wheel.into_iter().chain(in_coarse_wheel).min()
     }
 
-    pub fn expire_timers(&self) {
+    /// Grows the wheel so a timer expiring in `expires_in` fits in it without wrapping.
+    /// The wheel doubles in size until the timer fits or `max_num_buckets` is reached.
+    ///
+    /// Returns false if the timer would not fit even after growing.
+    pub fn extend_horizon(&self, expires_in: Duration) -> bool {
         let mut state = self.state.lock().unwrap();
+        let expires_in_ticks = state.ticks_in(expires_in);
+        self.extend_horizon_locked(&mut state, expires_in_ticks)
+    }
 
-        state.current_time = (state.current_time + 1) % self.num_buckets as u64;
+    fn extend_horizon_locked(&self, state: &mut State, expires_in_ticks: u64) -> bool {
+        let mut num_buckets = state.num_buckets();
 
-        let bucket_index = state.current_time as usize;
+        if expires_in_ticks <= num_buckets as u64 {
+            return true;
+        }
 
-        let bucket = std::mem::take(&mut state.timers[bucket_index]);
+        let max_num_buckets = match self.max_num_buckets {
+            None => return false,
+            Some(max_num_buckets) => max_num_buckets,
+        };
 
-        for timer in bucket.into_iter() {
-            (timer.expire_action)();
+        while (num_buckets as u64) < expires_in_ticks && num_buckets < max_num_buckets {
+            num_buckets = num_buckets.saturating_mul(2).min(max_num_buckets);
         }
+
+        state.extend_horizon(num_buckets);
+
+        expires_in_ticks <= num_buckets as u64
     }
-}
 
-pub fn per_tick_bookkeeping(registry: Weak<Registry>) {
-    loop {
-        std::thread::sleep(Duration::from_secs(1));
+    /// Starts a timer that expires after `expires_in`.
+    /// Timers that expire on the same tick run in the order they were started.
+    ///
+    /// Timers that expire in less than a tick expire on the next tick.
+    /// That includes timers started from an action: actions run without holding
+    /// the registry's lock, so they can start timers, but the bucket of the tick
+    /// being expired was taken out of the wheel before the actions started running,
+    /// so a timer started for that tick would never be seen by it.
+    ///
+    /// Registries built with `ZeroDelayPolicy::Immediate` run the action of a timer
+    /// that expires after `Duration::ZERO` before returning instead.
+    pub fn start_timer(
+        &self,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> TimerHandle {
+        self.insert_timer(expires_in, None, Action::Once(Box::new(expire_action)))
+    }
 
-        match registry.upgrade() {
-            None => {
-                return;
-            }
-            Some(registry) => {
-                registry.expire_timers();
-            }
-        }
+    /// Like `start_timer`, but the action is told when the timer fired,
+    /// to find out how late timers fire.
+    pub fn start_timer_with_info(
+        &self,
+        expires_in: Duration,
+        expire_action: impl FnOnce(FireInfo) + Send + Sync + 'static,
+    ) -> TimerHandle {
+        self.insert_timer(expires_in, None, Action::WithInfo(Box::new(expire_action)))
     }
-}
 
-type ExpireAction = dyn FnOnce() + Send + Sync;
+    /// Like `start_timer`, but the action is given `state` to read and change.
+    ///
+    /// `state` is locked right before the action runs and unlocked once it returns,
+    /// without holding the registry lock, so the action can use the registry too.
+    pub fn start_timer_with_state<S: Send + 'static>(
+        &self,
+        expires_in: Duration,
+        state: Arc<Mutex<S>>,
+        expire_action: impl FnOnce(&mut S) + Send + Sync + 'static,
+    ) -> TimerHandle {
+        self.start_timer(expires_in, move || {
+            expire_action(&mut lock_clearing_poison(&state));
+        })
+    }
 
-pub struct Timer {
-    id: usize,
-    expire_action: Box<ExpireAction>,
-}
+    /// Starts a timer that expires every `interval` until it is stopped.
+    ///
+    /// The timer is rescheduled relative to the tick it was meant to expire at,
+    /// so the nth expiration happens at tick `n * interval` no matter how long
+    /// it takes to run the action.
+    ///
+    /// `interval` is counted in whole ticks, like the delay of `start_timer`,
+    /// so intervals shorter than a tick expire every tick.
+    ///
+    /// Dropping the registry stops every periodic timer. Their actions are dropped
+    /// without running again, including the ones the watchdog stopped waiting for,
+    /// which are dropped once they finish instead of being restarted.
+    pub fn start_periodic_timer(
+        &self,
+        interval: Duration,
+        expire_action: impl FnMut() + Send + Sync + 'static,
+    ) -> TimerHandle {
+        let every = self.state.lock().unwrap().ticks_in(interval).max(1);
+        self.start_interval_ticks(every, expire_action)
+    }
 
-/// Can be used to interact with a Timer after it has been registered.
-/// Could be used to cancel a timer for example.
-pub struct TimerHandle {
-    /// The position of the bucket that the timer has been added to.
-    bucket_position: usize,
-    /// The timer identifier.
-    timer_id: usize,
-}
+    /// Starts a timer that expires every `every` ticks until it is stopped,
+    /// at ticks `every`, `2 * every`, `3 * every` and so on from now.
+    pub fn start_interval_ticks(
+        &self,
+        every: u64,
+        expire_action: impl FnMut() + Send + Sync + 'static,
+    ) -> TimerHandle {
+        assert!(
+            every > 0,
+            "periodic timers must have an interval of at least one tick"
+        );
 
-#[cfg(test)]
-mod tests {
-    use std::time::{Duration, Instant};
+        self.insert_timer_locked(
+            &mut self.state.lock().unwrap(),
+            every,
+            Some(every),
+            None,
+            Action::Periodic(Box::new(expire_action)),
+        )
+    }
 
-    use super::*;
+    /// Starts a timer that expires when the wheel reaches `tick`,
+    /// counting from the tick the registry was created at.
+    ///
+    /// Returns `ScheduleError::PastTick` if the wheel already reached `tick`,
+    /// instead of wrapping the timer into a bucket that is only reached a turn later.
+    pub fn start_timer_at_tick(
+        &self,
+        tick: u64,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Result<TimerHandle, ScheduleError> {
+        let mut state = self.state.lock().unwrap();
 
-    #[test]
-    pub fn simple() {
-        let registry = Registry::new();
+        if tick <= state.current_time {
+            return Err(ScheduleError::PastTick);
+        }
 
-        let start = Instant::now();
-        registry.start_timer(Duration::from_secs(1), move || {
+        let expires_in_ticks = tick - state.current_time;
+        Ok(self.insert_timer_locked(
+            &mut state,
+            expires_in_ticks,
+            None,
+            None,
+            Action::Once(Box::new(expire_action)),
+        ))
+    }
+
+    /// Starts a timer that expires `when` says, so callers that get the expiration
+    /// in different forms don't have to pick between `start_timer` and `start_timer_at_tick`.
+    ///
+    /// Returns `ScheduleError::PastTick` for a `When::AtTick` the wheel already reached.
+    pub fn schedule(
+        &self,
+        when: When,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> Result<TimerHandle, ScheduleError> {
+        match when {
+            When::In(expires_in) => Ok(self.start_timer(expires_in, expire_action)),
+            When::At(instant) => {
+                let mut state = self.state.lock().unwrap();
+                let expires_in_ticks = state
+                    .tick_at(instant)
+                    .saturating_sub(state.current_time)
+                    .max(1);
+                Ok(self.insert_timer_locked(
+                    &mut state,
+                    expires_in_ticks,
+                    None,
+                    None,
+                    Action::Once(Box::new(expire_action)),
+                ))
+            }
+            When::AtTick(tick) => self.start_timer_at_tick(tick, expire_action),
+        }
+    }
+
+    /// Starts a timer that expires after `expires_in`, unless a timer started
+    /// with the same `key` is still pending, so the same work scheduled twice only runs once.
+    ///
+    /// Which of the two timers is kept depends on the `KeyPolicy` the registry was built with.
+    /// The action of the other one is dropped without running.
+    /// Returns the handle of the timer that was kept.
+    ///
+    /// Timers that expire in less than a tick expire on the next tick,
+    /// whatever the `ZeroDelayPolicy`.
+    pub fn start_timer_keyed(
+        &self,
+        key: u64,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> TimerHandle {
+        let mut state = self.state.lock().unwrap();
+
+        let expires_in_ticks = state.ticks_in(expires_in).max(1);
+
+        if let Some(pending) = state.keys.get(&key).copied() {
+            if self.key_policy == KeyPolicy::KeepEarliest
+                && pending.expires_at <= state.current_time + expires_in_ticks
+            {
+                return pending;
+            }
+
+            let _ = state.take(&pending);
+        }
+
+        let handle = self.insert_timer_locked(
+            &mut state,
+            expires_in_ticks,
+            None,
+            Some(key),
+            Action::Once(Box::new(expire_action)),
+        );
+        state.keys.insert(key, handle);
+
+        handle
+    }
+
+    fn insert_timer(
+        &self,
+        expires_in: Duration,
+        interval: Option<u64>,
+        action: Action,
+    ) -> TimerHandle {
+        let mut state = self.state.lock().unwrap();
+
+        if expires_in.is_zero()
+            && interval.is_none()
+            && self.zero_delay_policy == ZeroDelayPolicy::Immediate
+        {
+            let timer = Timer {
+                id: state.next_timer_id,
+                expires_at: state.current_time,
+                interval,
+                key: None,
+                action,
+            };
+            state.next_timer_id = state.next_timer_id.saturating_add(1);
+            drop(state);
+
+            let handle = TimerHandle {
+                expires_at: timer.expires_at,
+                interval,
+                timer_id: timer.id,
+            };
+
+            self.run_actions(vec![timer], handle.expires_at);
+
+            return handle;
+        }
+
+        // The bucket of the current tick has already been expired,
+        // or is being expired by the action starting this timer.
+        let expires_in_ticks = state.ticks_in(expires_in).max(1);
+
+        self.insert_timer_locked(&mut state, expires_in_ticks, interval, None, action)
+    }
+
+    fn insert_timer_locked(
+        &self,
+        state: &mut State,
+        expires_in_ticks: u64,
+        interval: Option<u64>,
+        key: Option<u64>,
+        action: Action,
+    ) -> TimerHandle {
+        let timer_id = state.next_timer_id;
+        state.next_timer_id = state.next_timer_id.saturating_add(1);
+
+        // Timers that still don't fit go to the coarse wheel.
+        let _ = self.extend_horizon_locked(state, expires_in_ticks);
+
+        let expires_at = state.current_time + expires_in_ticks;
+
+        state.place(Timer {
+            id: timer_id,
+            expires_at,
+            interval,
+            key,
+            action,
+        });
+
+        TimerHandle {
+            expires_at,
+            interval,
+            timer_id,
+        }
+    }
+
+    /// Returns a receiver that gets the id of every timer that expires from now on,
+    /// after its action runs.
+    ///
+    /// Ids are dropped for subscribers that fall too far behind
+    /// and subscribers whose receiver has been dropped are forgotten.
+    pub fn subscribe(&self) -> Receiver<u64> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn notify_subscribers(&self, timer_id: usize) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        subscribers.retain(|subscriber| {
+            !matches!(
+                subscriber.try_send(timer_id as u64),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    /// Returns true if the timer `timer_handle` points to is still pending.
+    ///
+    /// Periodic timers whose action is running are not pending until they are restarted.
+    pub fn contains(&self, timer_handle: &TimerHandle) -> bool {
+        let state = self.state.lock().unwrap();
+
+        let expires_at = state.next_expiration(timer_handle);
+        let bucket_position = state.bucket_position(expires_at);
+        let coarse_bucket_position = state.coarse_bucket_position(expires_at);
+
+        state.timers[bucket_position]
+            .iter()
+            .chain(
+                state
+                    .coarse_timers
+                    .get(coarse_bucket_position)
+                    .into_iter()
+                    .flatten(),
+            )
+            .any(|timer| timer.id == timer_handle.timer_id)
+    }
+
+    /// Stops the timer `timer_handle` points to.
+    ///
+    /// In debug builds, panics if the timer should still be pending but is not in the wheel,
+    /// e.g. because it was already stopped or the handle belongs to another registry.
+    pub fn stop_timer(&self, timer_handle: &TimerHandle) {
+        let mut state = self.state.lock().unwrap();
+
+        let expires_at = state.next_expiration(timer_handle);
+
+        if state.take(timer_handle).is_some() {
+            return;
+        }
+
+        match timer_handle.interval {
+            // The timer may be expiring right now.
+            Some(_) => {
+                state.stopped_while_expiring.insert(timer_handle.timer_id);
+            }
+            None => debug_assert!(
+                expires_at <= state.current_time
+                    || state.fired_early.contains_key(&timer_handle.timer_id),
+                "stale handle: timer {} should be pending until tick {} but is not in the wheel",
+                timer_handle.timer_id,
+                expires_at
+            ),
+        }
+    }
+
+    /// Runs the action of the pending timer `timer_handle` points to right away,
+    /// on the calling thread and without holding the registry lock.
+    ///
+    /// One-shot timers are removed from the wheel. Periodic timers go back to the wheel
+    /// and still expire at the tick they were waiting for.
+    ///
+    /// Returns false if the timer already expired or was stopped.
+    pub fn fire_now(&self, timer_handle: &TimerHandle) -> bool {
+        let (current_time, timer) = {
+            let mut state = self.state.lock().unwrap();
+            let expires_at = state.next_expiration(timer_handle);
+            match state.take(timer_handle) {
+                None => return false,
+                Some(timer) => {
+                    if timer_handle.interval.is_none() {
+                        state.fired_early.insert(timer.id, expires_at);
+                    }
+                    (state.current_time, timer)
+                }
+            }
+        };
+
+        let timer_id = timer.id;
+
+        if let Some(timer) = timer.run(current_time) {
+            let mut state = self.state.lock().unwrap();
+            if !state.stopped_while_expiring.remove(&timer_id) {
+                state.place(timer);
+            }
+        }
+
+        self.notify_subscribers(timer_id);
+
+        true
+    }
+
+    /// Advances the wheel by one tick and runs the actions of the timers in the new bucket.
+    ///
+    /// Timers that expire on the same tick run in the order they were started,
+    /// unless the registry was built with an `expiry_parallelism` above one or a watchdog.
+    /// Actions run without holding the registry lock, so they can start and stop timers.
+    /// When a lot of timers expire on the same tick, other threads get a chance
+    /// to use the registry every `expire_chunk_size` actions.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn expire_timers(&self) -> usize {
+        self.tick(lock_clearing_poison(&self.expiring))
+    }
+
+    /// Like `expire_timers`, but does nothing and returns false
+    /// if another thread is already expiring timers.
+    ///
+    /// Lets an event loop drive the wheel without waiting for the background thread
+    /// to run the actions of a tick.
+    pub fn try_tick(&self) -> bool {
+        match self.expiring.try_lock() {
+            Err(TryLockError::WouldBlock) => false,
+            Err(TryLockError::Poisoned(poisoned)) => {
+                self.expiring.clear_poison();
+                self.tick(poisoned.into_inner());
+                true
+            }
+            Ok(expiring) => {
+                self.tick(expiring);
+                true
+            }
+        }
+    }
+
+    /// Advances the wheel by one tick. `_expiring` keeps other ticks from running at the same time.
+    fn tick(&self, _expiring: MutexGuard<'_, ()>) -> usize {
+        let (current_time, bucket_index, mut bucket) = {
+            let mut state = self.state.lock().unwrap();
+
+            state.current_time += 1;
+            state.cascade();
+
+            let current_time = state.current_time;
+            state
+                .fired_early
+                .retain(|_, expires_at| *expires_at > current_time);
+
+            let bucket_index = state.bucket_position(state.current_time);
+
+            let bucket = std::mem::take(&mut state.timers[bucket_index]);
+
+            // The keys are free again as soon as their timers expire,
+            // so actions can start a timer with their own key.
+            for key in bucket.iter().filter_map(|timer| timer.key) {
+                state.keys.remove(&key);
+            }
+
+            (state.current_time, bucket_index, bucket)
+        };
+
+        let mut periodic_timers = Vec::new();
+        let num_expired = bucket.len();
+
+        if self.expiry_parallelism > 1 && num_expired > 1 {
+            periodic_timers = self.expire_in_parallel(&mut bucket, current_time);
+        }
+
+        let mut timers = bucket.drain(..);
+        loop {
+            let chunk: Vec<Timer> = timers.by_ref().take(self.expire_chunk_size).collect();
+            if chunk.is_empty() {
+                break;
+            }
+
+            periodic_timers.extend(self.run_actions(chunk, current_time));
+
+            if timers.len() > 0 {
+                self.restart_periodic_timers(&mut self.state.lock().unwrap(), &mut periodic_timers);
+                std::thread::yield_now();
+            }
+        }
+        drop(timers);
+
+        let mut state = self.state.lock().unwrap();
+
+        self.collect_in_flight_timers(&state, &mut periodic_timers);
+        self.restart_periodic_timers(&mut state, &mut periodic_timers);
+
+        match &self.watchdog {
+            None => state.stopped_while_expiring.clear(),
+            Some(watchdog) => {
+                // Timers with actions in flight can still be stopped before they are restarted.
+                let in_flight = watchdog.in_flight.lock().unwrap();
+                state
+                    .stopped_while_expiring
+                    .retain(|id| in_flight.iter().any(|(timer_id, _)| timer_id == id));
+            }
+        }
+
+        // Give the bucket its allocation back so it doesn't have to grow again
+        // the next time the wheel goes around. See `compact`.
+        bucket.append(&mut state.timers[bucket_index]);
+        state.timers[bucket_index] = bucket;
+
+        num_expired
+    }
+
+    /// Splits the timers in `bucket`, which fired at `fired_tick`, between
+    /// `expiry_parallelism` threads that run their share of the actions.
+    ///
+    /// Returns the periodic timers that have to be restarted.
+    fn expire_in_parallel(&self, bucket: &mut Vec<Timer>, fired_tick: u64) -> Vec<Timer> {
+        let share_len = bucket.len().div_ceil(self.expiry_parallelism);
+        let mut timers = bucket.drain(..);
+
+        std::thread::scope(|scope| {
+            let mut threads = Vec::new();
+
+            loop {
+                let share: Vec<Timer> = timers.by_ref().take(share_len).collect();
+                if share.is_empty() {
+                    break;
+                }
+
+                threads.push(scope.spawn(move || self.run_actions(share, fired_tick)));
+            }
+
+            threads
+                .into_iter()
+                .flat_map(|thread| match thread.join() {
+                    Ok(periodic_timers) => periodic_timers,
+                    Err(panic) => std::panic::resume_unwind(panic),
+                })
+                .collect()
+        })
+    }
+
+    /// Runs the actions of `timers`, which fired at `fired_tick`, and tells the subscribers.
+    ///
+    /// Without a watchdog the actions run one after the other on the calling thread.
+    /// With one, every action is started on the watchdog's threads before it waits
+    /// for any of them, so the actions only hold the tick up until the last deadline.
+    ///
+    /// Returns the periodic timers that have to be restarted.
+    fn run_actions(&self, timers: Vec<Timer>, fired_tick: u64) -> Vec<Timer> {
+        let Some(watchdog) = &self.watchdog else {
+            let mut periodic_timers = Vec::new();
+
+            for timer in timers {
+                let timer_id = timer.id;
+
+                if let Some(timer) = timer.run(fired_tick) {
+                    periodic_timers.push(timer);
+                }
+
+                self.notify_subscribers(timer_id);
+            }
+
+            return periodic_timers;
+        };
+
+        let watched: Vec<_> = timers
+            .into_iter()
+            .map(|timer| (timer.id, watchdog.start(timer, fired_tick)))
+            .collect();
+
+        watched
+            .into_iter()
+            .filter_map(|(timer_id, watched)| {
+                let timer = watchdog.wait(watched);
+                self.notify_subscribers(timer_id);
+                timer
+            })
+            .collect()
+    }
+
+    /// Moves the periodic timers whose actions finished after the watchdog
+    /// stopped waiting for them to `periodic_timers`.
+    fn collect_in_flight_timers(&self, state: &State, periodic_timers: &mut Vec<Timer>) {
+        let Some(watchdog) = &self.watchdog else {
+            return;
+        };
+
+        watchdog
+            .in_flight
+            .lock()
+            .unwrap()
+            .retain(|(_, receiver)| match receiver.try_recv() {
+                Err(TryRecvError::Empty) => true,
+                Ok(None) | Err(TryRecvError::Disconnected) => false,
+                Ok(Some(mut timer)) => {
+                    // Skip the expirations that were missed while the action was running.
+                    let interval = timer.interval.unwrap();
+                    let missed = state.current_time.saturating_sub(timer.expires_at) / interval;
+                    timer.expires_at += missed * interval;
+                    periodic_timers.push(timer);
+                    false
+                }
+            });
+    }
+
+    fn restart_periodic_timers(&self, state: &mut State, periodic_timers: &mut Vec<Timer>) {
+        for mut timer in periodic_timers.drain(..) {
+            if state.stopped_while_expiring.remove(&timer.id) {
+                continue;
+            }
+
+            timer.expires_at += timer.interval.unwrap();
+            state.place(timer);
+        }
+    }
+
+    /// Advances the wheel to the tick `now` falls in, running every timer that
+    /// expires until then on the calling thread before returning.
+    ///
+    /// Does nothing if the wheel is already at or past that tick.
+    pub fn flush(&self, now: Instant) {
+        let _ = self.expire_timers_at(now);
+    }
+
+    /// Advances the wheel through every tick up to the one `target` falls in and runs
+    /// the timers that expire on them, earliest first, before returning.
+    ///
+    /// Meant for simulations built on `Builder::build_registry`, which move time along
+    /// in jumps instead of waiting for the background thread.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn advance_to(&self, target: Instant) -> usize {
+        self.expire_timers_at(target).num_expired
+    }
+
+    /// Like `flush`, but returns how many ticks the wheel had to advance to catch up with `now`.
+    pub fn expire_timers_at(&self, now: Instant) -> ExpireReport {
+        let target_tick = self.state.lock().unwrap().tick_at(now);
+
+        let mut report = ExpireReport {
+            ticks_advanced: 0,
+            num_expired: 0,
+        };
+
+        loop {
+            let expiring = lock_clearing_poison(&self.expiring);
+
+            if self.state.lock().unwrap().current_time >= target_tick {
+                return report;
+            }
+
+            report.num_expired += self.tick(expiring);
+            report.ticks_advanced += 1;
+        }
+    }
+
+    /// Makes room for `total` more timers spread evenly across the buckets of the wheel,
+    /// so starting them doesn't have to grow the buckets one at a time.
+    ///
+    /// Timers that don't fit in a bucket's share still grow it as usual.
+    pub fn reserve(&self, total: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        let per_bucket = total.div_ceil(state.num_buckets());
+
+        for bucket in state.timers.iter_mut() {
+            bucket.reserve(per_bucket);
+        }
+    }
+
+    /// Releases the memory held by buckets that are bigger than they need to be
+    /// because they held more timers in the past.
+    pub fn compact(&self) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        for bucket in state
+            .timers
+            .iter_mut()
+            .chain(state.coarse_timers.iter_mut())
+        {
+            bucket.shrink_to_fit();
+        }
+    }
+}
+
+/// Locks `mutex`, clearing the poison left by an action that panicked while it was held.
+///
+/// Timers are taken out of their bucket before their actions run,
+/// so the wheel is left as it was before the tick started running actions.
+fn lock_clearing_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Removes the timer with `timer_id` from `bucket`.
+///
+/// Returns false if the timer is not in `bucket`.
+fn take_timer(bucket: &mut Vec<Timer>, timer_id: usize) -> Option<Timer> {
+    // TODO: this is slow but that's okay for now.
+    let index = bucket.iter().position(|timer| timer.id == timer_id)?;
+    Some(bucket.remove(index))
+}
+
+/// A wheel with a number of buckets known at compile time.
+///
+/// The buckets live inline in the registry instead of in a heap allocated `Vec`,
+/// so the memory layout of the wheel is known up front.
+/// Timers that expire more than `N` ticks in the future stay in their bucket
+/// until the turn of the wheel they expire in.
+pub struct FixedRegistry<const N: usize> {
+    state: Mutex<FixedState<N>>,
+}
+
+pub struct FixedState<const N: usize> {
+    next_timer_id: usize,
+    /// The number of ticks since the registry was created.
+    current_time: u64,
+    timers: [Vec<Timer>; N],
+}
+
+impl<const N: usize> FixedRegistry<N> {
+    /// Creates a registry that is ticked by a background thread,
+    /// unless the `background-thread` feature is disabled.
+    pub fn new() -> Arc<Self> {
+        let registry = Arc::new(Self::without_bookkeeping());
+
+        #[cfg(feature = "background-thread")]
+        {
+            let registry_clone = Arc::downgrade(&registry);
+            std::thread::spawn(move || fixed_per_tick_bookkeeping(registry_clone));
+        }
+
+        registry
+    }
+
+    /// Creates a registry that is not ticked by a background thread.
+    /// Timers only expire when `expire_timers` is called.
+    pub fn without_bookkeeping() -> Self {
+        const { assert!(N > 0, "the wheel needs at least one bucket") };
+
+        Self {
+            state: Mutex::new(FixedState {
+                next_timer_id: 0,
+                current_time: 0,
+                timers: std::array::from_fn(|_| Vec::new()),
+            }),
+        }
+    }
+
+    /// Starts a timer that expires after `expires_in`.
+    /// Timers that expire on the same tick run in the order they were started.
+    ///
+    /// Timers that expire in less than a tick expire on the next tick,
+    /// since the bucket of the current tick has already been expired.
+    pub fn start_timer(
+        &self,
+        expires_in: Duration,
+        expire_action: impl FnOnce() + Send + Sync + 'static,
+    ) -> TimerHandle {
+        let mut state = lock_clearing_poison(&self.state);
+
+        let timer_id = state.next_timer_id;
+        state.next_timer_id = state.next_timer_id.saturating_add(1);
+
+        let expires_at = state.current_time + expires_in.as_secs().max(1);
+        let bucket_position = expires_at as usize % N;
+
+        state.timers[bucket_position].push(Timer {
+            id: timer_id,
+            expires_at,
+            interval: None,
+            key: None,
+            action: Action::Once(Box::new(expire_action)),
+        });
+
+        TimerHandle {
+            expires_at,
+            interval: None,
+            timer_id,
+        }
+    }
+
+    pub fn stop_timer(&self, timer_handle: &TimerHandle) {
+        let mut state = lock_clearing_poison(&self.state);
+
+        let bucket_position = timer_handle.expires_at as usize % N;
+
+        let index = state.timers[bucket_position]
+            .iter()
+            .position(|timer| timer.id == timer_handle.timer_id);
+
+        if let Some(index) = index {
+            state.timers[bucket_position].remove(index);
+        }
+    }
+
+    /// Advances the wheel by one tick and runs the actions of the timers in the new bucket
+    /// that expire on it.
+    ///
+    /// Returns the number of actions that ran.
+    pub fn expire_timers(&self) -> usize {
+        let mut state = lock_clearing_poison(&self.state);
+
+        state.current_time += 1;
+
+        let current_time = state.current_time;
+        let bucket_index = current_time as usize % N;
+
+        // Timers for a later turn of the wheel share the bucket.
+        let (expired, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut state.timers[bucket_index])
+            .into_iter()
+            .partition(|timer| timer.expires_at <= current_time);
+        state.timers[bucket_index] = pending;
+
+        let num_expired = expired.len();
+
+        for timer in expired.into_iter() {
+            match timer.action {
+                Action::Once(expire_action) => (expire_action)(),
+                Action::Periodic(_) | Action::WithInfo(_) => {
+                    unreachable!("fixed registries only hold timers started with start_timer")
+                }
+            }
+        }
+
+        num_expired
+    }
+}
+
+#[cfg(feature = "background-thread")]
+fn fixed_per_tick_bookkeeping<const N: usize>(registry: Weak<FixedRegistry<N>>) {
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+
+        match registry.upgrade() {
+            None => {
+                return;
+            }
+            Some(registry) => {
+                // The timers after a panicking action in its bucket are lost,
+                // but the wheel keeps going.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| registry.expire_timers()));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "background-thread")]
+pub fn per_tick_bookkeeping(registry: Weak<Registry>) {
+    if let Some(registry) = registry.upgrade() {
+        registry.mark_started();
+    }
+
+    loop {
+        let tick_duration = match registry.upgrade() {
+            None => {
+                return;
+            }
+            Some(registry) => registry.tick_duration(),
+        };
+
+        std::thread::sleep(tick_duration);
+
+        match registry.upgrade() {
+            None => {
+                return;
+            }
+            Some(registry) => {
+                // Catches up with the ticks that were missed if the process was suspended.
+                // A panicking action loses the rest of its bucket but doesn't stop the thread.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    registry.expire_timers_at(Instant::now())
+                }));
+            }
+        }
+    }
+}
+
+type ExpireAction = dyn FnOnce() + Send + Sync;
+type PeriodicExpireAction = dyn FnMut() + Send + Sync;
+type InfoExpireAction = dyn FnOnce(FireInfo) + Send + Sync;
+
+enum Action {
+    Once(Box<ExpireAction>),
+    Periodic(Box<PeriodicExpireAction>),
+    WithInfo(Box<InfoExpireAction>),
+}
+
+pub struct Timer {
+    id: usize,
+    /// The tick at which the timer expires.
+    expires_at: u64,
+    /// The number of ticks between expirations of a periodic timer.
+    interval: Option<u64>,
+    /// The key the timer was started with by `Registry::start_timer_keyed`.
+    key: Option<u64>,
+    action: Action,
+}
+
+impl Timer {
+    /// Runs the action of the timer, which fired at `fired_tick`.
+    ///
+    /// Returns the timer if it is periodic and has to be restarted.
+    fn run(mut self, fired_tick: u64) -> Option<Timer> {
+        match self.action {
+            Action::Once(expire_action) => {
+                (expire_action)();
+                None
+            }
+            Action::WithInfo(expire_action) => {
+                (expire_action)(FireInfo {
+                    fired_tick,
+                    scheduled_tick: self.expires_at,
+                    wall_time: Instant::now(),
+                });
+                None
+            }
+            Action::Periodic(ref mut expire_action) => {
+                (expire_action)();
+                Some(self)
+            }
+        }
+    }
+}
+
+/// Can be used to interact with a Timer after it has been registered.
+/// Could be used to cancel a timer for example.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerHandle {
+    /// The tick at which the timer expires.
+    /// Used to find the bucket that the timer has been added to.
+    expires_at: u64,
+    /// The number of ticks between expirations of a periodic timer.
+    interval: Option<u64>,
+    /// The timer identifier.
+    timer_id: usize,
+}
+
+impl TimerHandle {
+    /// Returns the id subscribers receive when the timer expires.
+    pub fn id(&self) -> u64 {
+        self.timer_id as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::AssertUnwindSafe,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "background-thread")]
+    pub fn simple() {
+        let registry = Registry::new();
+
+        let start = Instant::now();
+        registry.start_timer(Duration::from_secs(1), move || {
             println!("expired 1 sec. time={:?}", start.elapsed());
         });
 
         let start = Instant::now();
-        registry.start_timer(Duration::from_secs(3), move || {
-            println!("expired 3 sec. time={:?}", start.elapsed());
+        registry.start_timer(Duration::from_secs(3), move || {
+            println!("expired 3 sec. time={:?}", start.elapsed());
+        });
+
+        std::thread::sleep(Duration::from_secs(5));
+    }
+
+    #[test]
+    fn grows_to_fit_timer_beyond_horizon() {
+        let registry = Registry::builder()
+            .num_buckets(4)
+            .max_num_buckets(64)
+            .build_registry();
+
+        let fired = Arc::new(AtomicBool::new(false));
+
+        // Advance the clock so the wheel is not aligned with bucket 0 when it grows.
+        registry.expire_timers();
+
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(2), || {});
+        registry.start_timer(Duration::from_secs(10), move || {
+            fired_clone.store(true, Ordering::SeqCst);
+        });
+
+        assert_eq!(16, registry.num_buckets());
+
+        for _ in 0..9 {
+            registry.expire_timers();
+            assert!(!fired.load(Ordering::SeqCst));
+        }
+
+        registry.expire_timers();
+        assert!(fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn growth_is_capped() {
+        let registry = Registry::builder()
+            .num_buckets(4)
+            .max_num_buckets(8)
+            .build_registry();
+
+        assert!(!registry.extend_horizon(Duration::from_secs(100)));
+        assert_eq!(8, registry.num_buckets());
+
+        let registry = Registry::builder().num_buckets(4).build_registry();
+        assert!(!registry.extend_horizon(Duration::from_secs(5)));
+        assert_eq!(4, registry.num_buckets());
+    }
+
+    #[test]
+    fn periodic_timer_does_not_drift() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+
+        let fired_clone = Arc::clone(&fired);
+        let handle = registry.start_periodic_timer(Duration::from_secs(3), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+            // Pretend the action takes a while to run.
+            std::thread::sleep(Duration::from_millis(1));
+        });
+
+        let mut fired_at = Vec::new();
+        for tick in 1..=300 {
+            let before = fired.load(Ordering::SeqCst);
+            registry.expire_timers();
+            if fired.load(Ordering::SeqCst) > before {
+                fired_at.push(tick);
+            }
+        }
+
+        assert_eq!((1..=100).map(|n| n * 3).collect::<Vec<_>>(), fired_at);
+
+        registry.stop_timer(&handle);
+        for _ in 0..10 {
+            registry.expire_timers();
+        }
+        assert_eq!(100, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn periodic_timers_shorter_than_a_tick_expire_every_tick() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+
+        let fired_clone = Arc::clone(&fired);
+        registry.start_periodic_timer(Duration::from_millis(500), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        for _ in 0..3 {
+            registry.expire_timers();
+        }
+        assert_eq!(3, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn timers_expiring_on_the_same_tick_fire_in_insertion_order() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..5 {
+            let fired = Arc::clone(&fired);
+            // Timers 1 and 3 expire on a different tick.
+            let expires_in = if i % 2 == 0 { 2 } else { 1 };
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired.lock().unwrap().push(i);
+            });
+        }
+
+        registry.expire_timers();
+        assert_eq!(vec![1, 3], *fired.lock().unwrap());
+
+        registry.expire_timers();
+        assert_eq!(vec![1, 3, 0, 2, 4], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn compact_releases_bucket_memory() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        for _ in 0..1000 {
+            registry.start_timer(Duration::from_secs(1), || {});
+        }
+        registry.expire_timers();
+
+        let capacity = registry.state.lock().unwrap().timers[1].capacity();
+        assert!(capacity >= 1000);
+
+        registry.compact();
+
+        let capacity = registry.state.lock().unwrap().timers[1].capacity();
+        assert_eq!(0, capacity);
+    }
+
+    #[test]
+    fn reserve_makes_room_in_every_bucket() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        registry.reserve(75);
+
+        let capacities: Vec<usize> = registry
+            .state
+            .lock()
+            .unwrap()
+            .timers
+            .iter()
+            .map(Vec::capacity)
+            .collect();
+        assert!(capacities.iter().all(|&capacity| capacity >= 10));
+
+        for _ in 0..10 {
+            registry.start_timer(Duration::from_secs(3), || {});
+        }
+
+        let capacity = registry.state.lock().unwrap().timers[3].capacity();
+        assert_eq!(capacities[3], capacity);
+    }
+
+    #[test]
+    fn buckets_start_with_the_capacity_they_were_built_with() {
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .max_num_buckets(16)
+            .bucket_capacity(4)
+            .build_registry();
+
+        let (capacity, buffer) = {
+            let state = registry.state.lock().unwrap();
+            assert!(state.timers.iter().all(|bucket| bucket.capacity() >= 4));
+            (state.timers[3].capacity(), state.timers[3].as_ptr())
+        };
+
+        for _ in 0..4 {
+            registry.start_timer(Duration::from_secs(3), || {});
+        }
+
+        {
+            let state = registry.state.lock().unwrap();
+            assert_eq!(capacity, state.timers[3].capacity());
+            assert_eq!(buffer, state.timers[3].as_ptr());
+        }
+
+        // Buckets added when the wheel grows are sized too.
+        registry.start_timer(Duration::from_secs(12), || {});
+        let state = registry.state.lock().unwrap();
+        assert_eq!(16, state.num_buckets());
+        assert!(state.timers.iter().all(|bucket| bucket.capacity() >= 4));
+    }
+
+    #[test]
+    fn fixed_registry() {
+        let registry = FixedRegistry::<16>::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for expires_in in [1, 3, 16] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired.lock().unwrap().push(expires_in);
+            });
+        }
+
+        let fired_clone = Arc::clone(&fired);
+        let handle = registry.start_timer(Duration::from_secs(2), move || {
+            fired_clone.lock().unwrap().push(2);
+        });
+        registry.stop_timer(&handle);
+
+        let mut fired_at = Vec::new();
+        for tick in 1..=32 {
+            let before = fired.lock().unwrap().len();
+            registry.expire_timers();
+            if fired.lock().unwrap().len() > before {
+                fired_at.push(tick);
+            }
+        }
+
+        assert_eq!(vec![1, 3, 16], *fired.lock().unwrap());
+        assert_eq!(vec![1, 3, 16], fired_at);
+    }
+
+    #[test]
+    fn fixed_registry_fires_short_and_long_timers_on_their_tick() {
+        let registry = FixedRegistry::<4>::without_bookkeeping();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for expires_in in [
+            Duration::ZERO,
+            Duration::from_millis(500),
+            Duration::from_secs(6),
+        ] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(expires_in, move || {
+                fired.lock().unwrap().push(expires_in);
+            });
+        }
+
+        let fired_per_tick: Vec<usize> = (0..8).map(|_| registry.expire_timers()).collect();
+
+        // The timer a turn and a half away is skipped when the wheel first reaches its bucket.
+        assert_eq!(vec![2, 0, 0, 0, 0, 1, 0, 0], fired_per_tick);
+        assert_eq!(
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(500),
+                Duration::from_secs(6)
+            ],
+            *fired.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn try_tick_does_nothing_while_another_tick_is_running() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(1), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let expiring = registry.expiring.lock().unwrap();
+        assert!(!registry.try_tick());
+        drop(expiring);
+        assert_eq!(0, fired.load(Ordering::SeqCst));
+
+        assert!(registry.try_tick());
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn interval_ticks_timer_fires_every_nth_tick() {
+        let registry = Registry::builder().num_buckets(4).build_registry();
+
+        registry.expire_timers();
+
+        let fired_at = Arc::new(Mutex::new(Vec::new()));
+        let fired_at_clone = Arc::clone(&fired_at);
+        let registry = Arc::new(registry);
+        let registry_clone = Arc::downgrade(&registry);
+        let handle = registry.start_interval_ticks(3, move || {
+            let registry = registry_clone.upgrade().unwrap();
+            let current_time = registry.state.lock().unwrap().current_time;
+            fired_at_clone.lock().unwrap().push(current_time);
+        });
+
+        for _ in 0..10 {
+            registry.expire_timers();
+        }
+        assert_eq!(vec![4, 7, 10], *fired_at.lock().unwrap());
+
+        registry.stop_timer(&handle);
+        for _ in 0..6 {
+            registry.expire_timers();
+        }
+        assert_eq!(vec![4, 7, 10], *fired_at.lock().unwrap());
+    }
+
+    #[test]
+    fn expire_timers_at_reports_the_ticks_it_caught_up_on() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+        let started_at = registry.state.lock().unwrap().anchored_at;
+
+        for expires_in in [1, 50] {
+            registry.start_timer(Duration::from_secs(expires_in), || {});
+        }
+
+        let report = registry.expire_timers_at(started_at + Duration::from_millis(100_500));
+        assert_eq!(
+            ExpireReport {
+                ticks_advanced: 100,
+                num_expired: 2
+            },
+            report
+        );
+        assert!(report.coalesced());
+
+        let report = registry.expire_timers_at(started_at + Duration::from_secs(101));
+        assert_eq!(1, report.ticks_advanced);
+        assert!(!report.coalesced());
+
+        let report = registry.expire_timers_at(started_at + Duration::from_secs(101));
+        assert_eq!(0, report.ticks_advanced);
+    }
+
+    #[test]
+    fn flush_runs_every_timer_due_by_now() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+        let started_at = registry.state.lock().unwrap().anchored_at;
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for expires_in in [1, 5, 2] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired.lock().unwrap().push(expires_in);
+            });
+        }
+
+        registry.flush(started_at + Duration::from_millis(3500));
+        assert_eq!(vec![1, 2], *fired.lock().unwrap());
+
+        // The wheel is already at the tick, nothing else expires.
+        registry.flush(started_at + Duration::from_secs(3));
+        assert_eq!(vec![1, 2], *fired.lock().unwrap());
+
+        registry.flush(started_at + Duration::from_secs(5));
+        assert_eq!(vec![1, 2, 5], *fired.lock().unwrap());
+    }
+
+    #[test]
+    fn other_threads_can_use_the_registry_while_timers_expire() {
+        let registry = Arc::new(
+            Registry::builder()
+                .num_buckets(8)
+                .expire_chunk_size(100)
+                .build_registry(),
+        );
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let (started_timer_tx, started_timer_rx) = std::sync::mpsc::channel();
+        let started_timer_rx = Mutex::new(started_timer_rx);
+
+        for _ in 0..10_000 {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(1), move || {
+                fired.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // Blocks the expiration until another thread starts a timer.
+        registry.start_timer(Duration::from_secs(1), move || {
+            started_timer_rx
+                .lock()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5))
+                .expect("other thread could not start a timer");
+        });
+
+        let registry_clone = Arc::clone(&registry);
+        let fired_clone = Arc::clone(&fired);
+        let other_thread = std::thread::spawn(move || {
+            while fired_clone.load(Ordering::SeqCst) == 0 {
+                std::thread::yield_now();
+            }
+            registry_clone.start_timer(Duration::from_secs(1), || {});
+            started_timer_tx.send(()).unwrap();
+        });
+
+        registry.expire_timers();
+        other_thread.join().unwrap();
+
+        assert_eq!(10_000, fired.load(Ordering::SeqCst));
+        assert_eq!(1, registry.state.lock().unwrap().timers[2].len());
+    }
+
+    #[test]
+    fn periodic_timer_can_stop_itself() {
+        let registry = Arc::new(Registry::builder().num_buckets(8).build_registry());
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let handle = Arc::new(Mutex::new(None::<TimerHandle>));
+
+        let registry_clone = Arc::clone(&registry);
+        let fired_clone = Arc::clone(&fired);
+        let handle_clone = Arc::clone(&handle);
+        *handle.lock().unwrap() = Some(registry.start_periodic_timer(
+            Duration::from_secs(1),
+            move || {
+                if fired_clone.fetch_add(1, Ordering::SeqCst) == 2 {
+                    let handle = handle_clone.lock().unwrap();
+                    registry_clone.stop_timer(handle.as_ref().unwrap());
+                }
+            },
+        ));
+
+        for _ in 0..10 {
+            registry.expire_timers();
+        }
+
+        assert_eq!(3, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn timer_handle_can_be_logged() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let handle = registry.start_timer(Duration::from_secs(3), || {});
+
+        assert_eq!(
+            "TimerHandle { expires_at: 3, interval: None, timer_id: 0 }",
+            format!("{:?}", handle)
+        );
+    }
+
+    #[test]
+    fn every_subscriber_receives_expired_timer_ids() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let first = registry.subscribe();
+        let second = registry.subscribe();
+        let dropped = registry.subscribe();
+        drop(dropped);
+
+        let handles = [
+            registry.start_timer(Duration::from_secs(1), || {}),
+            registry.start_timer(Duration::from_secs(2), || {}),
+        ];
+        let ids: Vec<u64> = handles.iter().map(TimerHandle::id).collect();
+
+        registry.expire_timers();
+        registry.expire_timers();
+
+        assert_eq!(ids, first.try_iter().collect::<Vec<_>>());
+        assert_eq!(ids, second.try_iter().collect::<Vec<_>>());
+        assert_eq!(2, registry.subscribers.lock().unwrap().len());
+    }
+
+    #[test]
+    fn watchdog_reports_slow_actions_and_keeps_going() {
+        let timed_out = Arc::new(Mutex::new(Vec::new()));
+        let timed_out_clone = Arc::clone(&timed_out);
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .watchdog(Duration::from_millis(50), move |timer_id| {
+                timed_out_clone.lock().unwrap().push(timer_id);
+            })
+            .build_registry();
+
+        let slow = registry.start_timer(Duration::from_secs(1), || {
+            std::thread::sleep(Duration::from_secs(1));
+        });
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(1), move || {
+            fired_clone.store(true, Ordering::SeqCst);
+        });
+
+        let start = Instant::now();
+        registry.expire_timers();
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(fired.load(Ordering::SeqCst));
+        assert_eq!(vec![slow.id()], *timed_out.lock().unwrap());
+    }
+
+    #[test]
+    fn watchdog_waits_for_slow_actions_at_the_same_time_and_reuses_threads() {
+        let timed_out = Arc::new(AtomicUsize::new(0));
+        let timed_out_clone = Arc::clone(&timed_out);
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .watchdog(Duration::from_millis(100), move |_| {
+                timed_out_clone.fetch_add(1, Ordering::SeqCst);
+            })
+            .build_registry();
+
+        let finished = Arc::new(AtomicUsize::new(0));
+        for _ in 0..5 {
+            let finished = Arc::clone(&finished);
+            registry.start_timer(Duration::from_secs(1), move || {
+                std::thread::sleep(Duration::from_millis(300));
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let start = Instant::now();
+        registry.expire_timers();
+
+        // Waiting for them one by one would take five times as long.
+        assert!(start.elapsed() < Duration::from_millis(300));
+        assert_eq!(5, timed_out.load(Ordering::SeqCst));
+
+        let idle = |registry: &Registry| {
+            *registry
+                .watchdog
+                .as_ref()
+                .unwrap()
+                .threads
+                .idle
+                .lock()
+                .unwrap()
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while idle(&registry) < 5 {
+            assert!(Instant::now() < deadline, "the slow actions never finished");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(5, finished.load(Ordering::SeqCst));
+
+        // The next tick runs on the same threads.
+        for _ in 0..5 {
+            registry.start_timer(Duration::from_secs(1), || {});
+        }
+        registry.expire_timers();
+
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while idle(&registry) < 5 {
+            assert!(Instant::now() < deadline, "the actions never finished");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(5, idle(&registry));
+        assert_eq!(5, timed_out.load(Ordering::SeqCst));
+    }
+
+    /// Expires three slow actions on the same tick with at most one in flight.
+    ///
+    /// Returns how many actions started and the ids of the timers whose action was dropped.
+    fn expire_slow_actions(policy: InFlightPolicy) -> (usize, Vec<u64>) {
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let dropped_clone = Arc::clone(&dropped);
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .watchdog(Duration::from_millis(10), |_| {})
+            .max_in_flight(1, policy, move |timer_id| {
+                dropped_clone.lock().unwrap().push(timer_id);
+            })
+            .build_registry();
+
+        let started = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let started = Arc::clone(&started);
+            registry.start_timer(Duration::from_secs(1), move || {
+                started.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(100));
+            });
+        }
+
+        registry.expire_timers();
+
+        let dropped = dropped.lock().unwrap().clone();
+        (started.load(Ordering::SeqCst), dropped)
+    }
+
+    #[test]
+    fn in_flight_limit_drops_actions_when_full() {
+        assert_eq!((1, vec![1, 2]), expire_slow_actions(InFlightPolicy::Drop));
+    }
+
+    #[test]
+    fn in_flight_limit_waits_for_a_slot_when_blocking() {
+        assert_eq!(
+            (3, vec![]),
+            expire_slow_actions(InFlightPolicy::Block(Duration::from_secs(5)))
+        );
+        assert_eq!(
+            (1, vec![1, 2]),
+            expire_slow_actions(InFlightPolicy::Block(Duration::from_millis(10)))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "background-thread")]
+    fn wait_until_started_returns_before_the_first_tick() {
+        let registry = Registry::builder().num_buckets(8).build();
+
+        registry.wait_until_started();
+
+        assert_eq!(0, registry.state.lock().unwrap().current_time);
+    }
+
+    #[test]
+    fn expire_timers_returns_the_number_of_actions_that_ran() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        registry.start_timer(Duration::from_secs(1), || {});
+        registry.start_timer(Duration::from_secs(1), || {});
+        registry.start_periodic_timer(Duration::from_secs(2), || {});
+
+        assert_eq!(2, registry.expire_timers());
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(0, registry.expire_timers());
+        assert_eq!(1, registry.expire_timers());
+    }
+
+    #[test]
+    fn timers_beyond_the_horizon_cascade_from_the_coarse_wheel() {
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .num_coarse_buckets(4)
+            .build_registry();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        // 20 and 21 share a bucket with 4 and 5 but are two turns of the wheel away.
+        // 45 is further away than the coarse wheel reaches.
+        let mut handles = Vec::new();
+        for expires_in in [4, 20, 21, 45, 5, 37] {
+            let fired = Arc::clone(&fired);
+            handles.push(
+                registry.start_timer(Duration::from_secs(expires_in), move || {
+                    fired.lock().unwrap().push(expires_in);
+                }),
+            );
+        }
+        assert_eq!(8, registry.num_buckets());
+
+        registry.stop_timer(&handles[5]);
+
+        let mut fired_at = Vec::new();
+        for tick in 1..=50 {
+            let before = fired.lock().unwrap().len();
+            registry.expire_timers();
+            if fired.lock().unwrap().len() > before {
+                fired_at.push(tick);
+            }
+        }
+
+        assert_eq!(vec![4, 5, 20, 21, 45], *fired.lock().unwrap());
+        assert_eq!(vec![4, 5, 20, 21, 45], fired_at);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "stale handle")]
+    fn stopping_a_timer_twice_is_caught_in_debug_builds() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let handle = registry.start_timer(Duration::from_secs(3), || {});
+        registry.stop_timer(&handle);
+        registry.stop_timer(&handle);
+    }
+
+    #[test]
+    fn contains_is_true_until_the_timer_fires() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let handle = registry.start_timer(Duration::from_secs(2), || {});
+        let far_handle = registry.start_timer(Duration::from_secs(20), || {});
+
+        registry.expire_timers();
+        assert!(registry.contains(&handle));
+        assert!(registry.contains(&far_handle));
+
+        registry.expire_timers();
+        assert!(!registry.contains(&handle));
+        assert!(registry.contains(&far_handle));
+
+        registry.stop_timer(&far_handle);
+        assert!(!registry.contains(&far_handle));
+    }
+
+    #[test]
+    fn timers_beyond_the_horizon_overflow() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        assert_eq!(Duration::from_secs(8), registry.horizon());
+
+        registry.start_timer(registry.horizon(), || {});
+        assert_eq!(0, registry.overflow_len());
+
+        registry.start_timer(registry.horizon() + Duration::from_secs(1), || {});
+        registry.start_timer(Duration::from_secs(100), || {});
+        assert_eq!(2, registry.overflow_len());
+
+        for _ in 0..9 {
+            registry.expire_timers();
+        }
+        assert_eq!(1, registry.overflow_len());
+    }
+
+    #[test]
+    fn dropping_the_registry_drops_periodic_timers() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+
+        for every in [1, 2, 20] {
+            let fired = Arc::clone(&fired);
+            registry.start_interval_ticks(every, move || {
+                fired.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        for _ in 0..4 {
+            registry.expire_timers();
+        }
+        assert_eq!(6, fired.load(Ordering::SeqCst));
+        // Every action still holds its clone of the counter.
+        assert_eq!(4, Arc::strong_count(&fired));
+
+        drop(registry);
+        assert_eq!(1, Arc::strong_count(&fired));
+    }
+
+    #[test]
+    #[cfg(feature = "background-thread")]
+    fn periodic_timers_in_flight_are_not_restarted_after_the_registry_is_dropped() {
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .watchdog(Duration::from_millis(10), |_| {})
+            .build();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+
+        let fired_clone = Arc::clone(&fired);
+        registry.start_interval_ticks(1, move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+            // Outlives the watchdog so the timer is in flight when the registry is dropped.
+            std::thread::sleep(Duration::from_millis(300));
         });
 
-        std::thread::sleep(Duration::from_secs(5));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while fired.load(Ordering::SeqCst) == 0 {
+            assert!(Instant::now() < deadline, "the periodic timer never fired");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        drop(registry);
+
+        // The background thread may still be finishing a tick.
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while Arc::strong_count(&fired) > 1 {
+            assert!(
+                Instant::now() < deadline,
+                "the periodic timer was never dropped"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let fired_before = fired.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(1500));
+        assert_eq!(fired_before, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn timers_started_from_an_action_fire_on_a_later_tick() {
+        let registry = Arc::new(Registry::builder().num_buckets(8).build_registry());
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let weak_registry = Arc::downgrade(&registry);
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(1), move || {
+            fired_clone.lock().unwrap().push("first");
+
+            let registry = weak_registry.upgrade().unwrap();
+            for (delay, label) in [(0, "no delay"), (2, "two ticks")] {
+                let fired = Arc::clone(&fired_clone);
+                registry.start_timer(Duration::from_secs(delay), move || {
+                    fired.lock().unwrap().push(label);
+                });
+            }
+        });
+
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(vec!["first"], *fired.lock().unwrap());
+
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(vec!["first", "no delay"], *fired.lock().unwrap());
+
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(
+            vec!["first", "no delay", "two ticks"],
+            *fired.lock().unwrap()
+        );
+
+        // Timers started outside of an action for less than a tick don't wait a whole turn either.
+        registry.start_timer(Duration::from_millis(500), || {});
+        assert_eq!(1, registry.expire_timers());
+    }
+
+    #[test]
+    fn actions_are_told_the_tick_their_timer_fired_at() {
+        let registry = Registry::builder().num_buckets(4).build_registry();
+
+        let infos = Arc::new(Mutex::new(Vec::new()));
+
+        for expires_in in [1, 3, 10] {
+            let infos = Arc::clone(&infos);
+            registry.start_timer_with_info(Duration::from_secs(expires_in), move |info| {
+                infos.lock().unwrap().push(info);
+            });
+        }
+
+        let before = Instant::now();
+        for _ in 0..10 {
+            registry.expire_timers();
+        }
+
+        let infos = infos.lock().unwrap();
+        assert_eq!(
+            vec![(1, 1), (3, 3), (10, 10)],
+            infos
+                .iter()
+                .map(|info| (info.scheduled_tick, info.fired_tick))
+                .collect::<Vec<_>>()
+        );
+        assert!(infos.iter().all(|info| info.wall_time >= before));
+    }
+
+    #[test]
+    fn fire_now_runs_the_action_once() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        let handle = registry.start_timer(Duration::from_secs(10), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(registry.fire_now(&handle));
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+        assert!(!registry.contains(&handle));
+        assert!(!registry.fire_now(&handle));
+        // The handle is not stale, the timer already ran.
+        registry.stop_timer(&handle);
+
+        for _ in 0..10 {
+            registry.expire_timers();
+        }
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+        assert!(registry.state.lock().unwrap().fired_early.is_empty());
+        registry.stop_timer(&handle);
+
+        // Periodic timers keep their schedule.
+        let periodic_fired = Arc::new(AtomicUsize::new(0));
+        let periodic_fired_clone = Arc::clone(&periodic_fired);
+        let periodic = registry.start_interval_ticks(3, move || {
+            periodic_fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(registry.fire_now(&periodic));
+        for _ in 0..3 {
+            registry.expire_timers();
+        }
+        assert_eq!(2, periodic_fired.load(Ordering::SeqCst));
+        assert!(registry.contains(&periodic));
+    }
+
+    #[test]
+    fn ticks_keep_running_after_an_action_panics() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+
+        registry.start_timer(Duration::from_secs(1), || panic!("action panicked"));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(2), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| registry.expire_timers()));
+        assert!(result.is_err());
+        assert!(registry.expiring.is_poisoned());
+
+        assert!(registry.try_tick());
+        assert!(!registry.expiring.is_poisoned());
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+
+        let fixed = FixedRegistry::<4>::without_bookkeeping();
+        fixed.start_timer(Duration::from_secs(1), || panic!("action panicked"));
+        let fired_clone = Arc::clone(&fired);
+        fixed.start_timer(Duration::from_secs(2), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(std::panic::catch_unwind(AssertUnwindSafe(|| fixed.expire_timers())).is_err());
+        assert_eq!(1, fixed.expire_timers());
+        assert_eq!(2, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn start_timer_at_tick_rejects_past_ticks() {
+        let registry = Registry::builder().num_buckets(4).build_registry();
+
+        for _ in 0..5 {
+            registry.expire_timers();
+        }
+
+        assert_eq!(
+            Err(ScheduleError::PastTick),
+            registry.start_timer_at_tick(3, || panic!("scheduled in the past"))
+        );
+        assert_eq!(
+            Err(ScheduleError::PastTick),
+            registry.start_timer_at_tick(5, || panic!("scheduled in the past"))
+        );
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        registry
+            .start_timer_at_tick(11, move || {
+                fired_clone.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+
+        for _ in 5..10 {
+            registry.expire_timers();
+        }
+        assert_eq!(0, fired.load(Ordering::SeqCst));
+
+        registry.expire_timers();
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[cfg(not(feature = "background-thread"))]
+    fn registries_are_only_ticked_by_hand_without_a_background_thread() {
+        let registry = Registry::new();
+        registry.wait_until_started();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_secs(1), move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(0, fired.load(Ordering::SeqCst));
+
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn set_tick_duration_changes_how_durations_become_ticks() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let start = |expires_in: Duration, id: u64| {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(expires_in, move || fired.lock().unwrap().push(id));
+        };
+
+        // Two ticks of a second.
+        start(Duration::from_secs(2), 0);
+
+        registry.set_tick_duration(Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), registry.tick_duration());
+        assert_eq!(Duration::from_millis(800), registry.horizon());
+
+        start(Duration::from_millis(300), 1);
+        start(Duration::from_millis(150), 2);
+
+        registry.expire_timers();
+        assert_eq!(vec![2], *fired.lock().unwrap());
+        registry.expire_timers();
+        assert_eq!(vec![2, 0], *fired.lock().unwrap());
+        registry.expire_timers();
+        assert_eq!(vec![2, 0, 1], *fired.lock().unwrap());
+
+        // The wheel catches up with the time that passed at the new tick duration.
+        registry.flush(Instant::now() + Duration::from_millis(1050));
+        assert_eq!(10, registry.state.lock().unwrap().current_time);
+    }
+
+    #[test]
+    fn parallel_expiry_runs_every_action_exactly_once() {
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .expiry_parallelism(4)
+            .build_registry();
+
+        const NUM_TIMERS: usize = 1000;
+        let fired: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..NUM_TIMERS).map(|_| AtomicUsize::new(0)).collect());
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+
+        for id in 0..NUM_TIMERS {
+            let fired = Arc::clone(&fired);
+            let threads = Arc::clone(&threads);
+            registry.start_timer(Duration::from_secs(1), move || {
+                fired[id].fetch_add(1, Ordering::SeqCst);
+                threads.lock().unwrap().insert(std::thread::current().id());
+            });
+        }
+
+        let periodic_fired = Arc::new(AtomicUsize::new(0));
+        let periodic_fired_clone = Arc::clone(&periodic_fired);
+        registry.start_interval_ticks(1, move || {
+            periodic_fired_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(NUM_TIMERS + 1, registry.expire_timers());
+        assert!(fired.iter().all(|fired| fired.load(Ordering::SeqCst) == 1));
+        assert_eq!(4, threads.lock().unwrap().len());
+
+        // Periodic timers are restarted as usual.
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(2, periodic_fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn timers_with_the_same_key_fire_once() {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let record = |id: u64| {
+            let fired = Arc::clone(&fired);
+            move || fired.lock().unwrap().push(id)
+        };
+
+        let fired_at = |registry: &Registry, ticks: u64| {
+            let mut fired_at = Vec::new();
+            for tick in 1..=ticks {
+                registry.expire_timers();
+                fired_at.extend(fired.lock().unwrap().drain(..).map(|id| (id, tick)));
+            }
+            fired_at
+        };
+
+        let registry = Registry::builder().num_buckets(8).build_registry();
+
+        let first = registry.start_timer_keyed(7, Duration::from_secs(5), record(0));
+        let second = registry.start_timer_keyed(7, Duration::from_secs(3), record(1));
+        assert_ne!(first, second);
+        assert_eq!(
+            second,
+            registry.start_timer_keyed(7, Duration::from_secs(4), record(2))
+        );
+        assert_eq!(vec![(1, 3)], fired_at(&registry, 6));
+
+        // The key is free once its timer expired or was stopped.
+        let handle = registry.start_timer_keyed(7, Duration::from_secs(2), record(3));
+        registry.stop_timer(&handle);
+        registry.start_timer_keyed(7, Duration::from_secs(1), record(4));
+        assert_eq!(vec![(4, 1)], fired_at(&registry, 2));
+        assert!(registry.state.lock().unwrap().keys.is_empty());
+
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .key_policy(KeyPolicy::Replace)
+            .build_registry();
+
+        registry.start_timer_keyed(7, Duration::from_secs(3), record(5));
+        registry.start_timer_keyed(7, Duration::from_secs(5), record(6));
+        assert_eq!(vec![(6, 5)], fired_at(&registry, 6));
+    }
+
+    #[test]
+    fn advance_to_fires_every_due_timer_in_order() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+        let started_at = registry.state.lock().unwrap().anchored_at;
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        // Some of the timers are more than a revolution of the wheel away.
+        for (name, expires_in) in [
+            ("a", 20),
+            ("b", 3),
+            ("c", 12),
+            ("d", 3),
+            ("e", 1),
+            ("f", 30),
+        ] {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired.lock().unwrap().push(name);
+            });
+        }
+
+        assert_eq!(5, registry.advance_to(started_at + Duration::from_secs(25)));
+        assert_eq!(vec!["e", "b", "d", "c", "a"], *fired.lock().unwrap());
+        assert_eq!(25, registry.state.lock().unwrap().current_time);
+    }
+
+    #[test]
+    fn zero_delay_timers_fire_according_to_the_policy() {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        let registry = Registry::builder().num_buckets(8).build_registry();
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::ZERO, move || {
+            fired_clone.lock().unwrap().push("next tick");
+        });
+        assert!(fired.lock().unwrap().is_empty());
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(vec!["next tick"], *fired.lock().unwrap());
+
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .zero_delay_policy(ZeroDelayPolicy::Immediate)
+            .build_registry();
+        let fired_clone = Arc::clone(&fired);
+        let handle = registry.start_timer(Duration::ZERO, move || {
+            fired_clone.lock().unwrap().push("immediate");
+        });
+        assert_eq!(vec!["next tick", "immediate"], *fired.lock().unwrap());
+
+        // The timer never went to the wheel, so there is nothing to stop or expire.
+        registry.stop_timer(&handle);
+        assert_eq!(0, registry.expire_timers());
+
+        // Timers that expire in less than a tick but after some time still wait for the next tick.
+        let fired_clone = Arc::clone(&fired);
+        registry.start_timer(Duration::from_millis(1), move || {
+            fired_clone.lock().unwrap().push("one millisecond");
+        });
+        assert_eq!(2, fired.lock().unwrap().len());
+        assert_eq!(1, registry.expire_timers());
+        assert_eq!(
+            vec!["next tick", "immediate", "one millisecond"],
+            *fired.lock().unwrap()
+        );
+
+        // Actions that run right away are still watched.
+        let timed_out = Arc::new(Mutex::new(Vec::new()));
+        let timed_out_clone = Arc::clone(&timed_out);
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .zero_delay_policy(ZeroDelayPolicy::Immediate)
+            .watchdog(Duration::from_millis(50), move |timer_id| {
+                timed_out_clone.lock().unwrap().push(timer_id);
+            })
+            .build_registry();
+
+        let start = Instant::now();
+        let slow = registry.start_timer(Duration::ZERO, || {
+            std::thread::sleep(Duration::from_secs(1));
+        });
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(vec![slow.id()], *timed_out.lock().unwrap());
+    }
+
+    #[test]
+    fn schedule_accepts_every_kind_of_expiration() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+        let anchored_at = registry.state.lock().unwrap().anchored_at;
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let schedule = |when, name| {
+            let fired = Arc::clone(&fired);
+            registry.schedule(when, move || {
+                fired.lock().unwrap().push(name);
+            })
+        };
+
+        schedule(When::In(Duration::from_secs(2)), "in").unwrap();
+        schedule(When::At(anchored_at + Duration::from_millis(5500)), "at").unwrap();
+        schedule(When::AtTick(3), "at tick").unwrap();
+        schedule(When::At(anchored_at), "at the anchor").unwrap();
+        assert_eq!(
+            Err(ScheduleError::PastTick),
+            schedule(When::AtTick(0), "past tick")
+        );
+
+        let mut fired_at = Vec::new();
+        for tick in 1..=6 {
+            registry.expire_timers();
+            for name in fired.lock().unwrap().drain(..) {
+                fired_at.push((name, tick));
+            }
+        }
+
+        assert_eq!(
+            vec![("at the anchor", 1), ("in", 2), ("at tick", 3), ("at", 5)],
+            fired_at
+        );
+    }
+
+    #[test]
+    fn the_coarse_wheel_is_only_added_when_a_timer_needs_it() {
+        let registry = Registry::builder()
+            .num_buckets(8)
+            .num_coarse_buckets(4)
+            .build_registry();
+
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let start_timer = |expires_in: u64| {
+            let fired = Arc::clone(&fired);
+            registry.start_timer(Duration::from_secs(expires_in), move || {
+                fired.lock().unwrap().push(expires_in);
+            })
+        };
+
+        start_timer(8);
+        assert!(registry.state.lock().unwrap().coarse_timers.is_empty());
+
+        // Two turns of the wheel away, and the last tick of the second turn.
+        let handles = [start_timer(16), start_timer(19), start_timer(23)];
+        assert_eq!(4, registry.state.lock().unwrap().coarse_timers.len());
+        assert_eq!(3, registry.overflow_len());
+
+        let mut fired_at = Vec::new();
+        for tick in 1..=24 {
+            registry.expire_timers();
+            for expires_in in fired.lock().unwrap().drain(..) {
+                fired_at.push((expires_in, tick));
+            }
+
+            // The timers move to the wheel when the turn they expire in starts.
+            if tick == 15 {
+                assert_eq!(3, registry.overflow_len());
+            }
+            if tick == 16 {
+                assert_eq!(0, registry.overflow_len());
+                assert!(handles[1..].iter().all(|handle| registry.contains(handle)));
+            }
+        }
+
+        assert_eq!(vec![(8, 8), (16, 16), (19, 19), (23, 23)], fired_at);
+    }
+
+    #[test]
+    fn ticks_until_next_counts_from_the_current_tick() {
+        let registry = Registry::builder().num_buckets(8).build_registry();
+        assert_eq!(None, registry.ticks_until_next());
+
+        registry.start_timer(Duration::from_secs(30), || {});
+        assert_eq!(Some(30), registry.ticks_until_next());
+
+        let soonest = registry.start_timer(Duration::from_secs(6), || {});
+        assert_eq!(Some(6), registry.ticks_until_next());
+
+        // The bucket of the next timer is behind the current one, past the end of the wheel.
+        for _ in 0..5 {
+            registry.expire_timers();
+        }
+        registry.start_timer(Duration::from_secs(4), || {});
+        assert_eq!(Some(1), registry.ticks_until_next());
+
+        registry.stop_timer(&soonest);
+        assert_eq!(Some(4), registry.ticks_until_next());
+    }
+
+    #[test]
+    fn timers_with_state_change_the_shared_state() {
+        let registry = Arc::new(Registry::builder().num_buckets(8).build_registry());
+
+        #[derive(Default)]
+        struct Scheduler {
+            runs: Vec<u64>,
+            total: u64,
+        }
+
+        let scheduler = Arc::new(Mutex::new(Scheduler::default()));
+
+        for expires_in in [3, 1, 2] {
+            registry.start_timer_with_state(
+                Duration::from_secs(expires_in),
+                Arc::clone(&scheduler),
+                move |scheduler| {
+                    scheduler.runs.push(expires_in);
+                    scheduler.total += expires_in;
+                },
+            );
+        }
+
+        // The registry is not locked while the action runs.
+        let weak_registry = Arc::downgrade(&registry);
+        registry.start_timer_with_state(
+            Duration::from_secs(2),
+            Arc::clone(&scheduler),
+            move |_: &mut Scheduler| {
+                let registry = weak_registry.upgrade().unwrap();
+                registry.start_timer(Duration::from_secs(1), || {});
+            },
+        );
+
+        let num_expired: usize = (0..4).map(|_| registry.expire_timers()).sum();
+        assert_eq!(5, num_expired);
+
+        let scheduler = scheduler.lock().unwrap();
+        assert_eq!(vec![1, 2, 3], scheduler.runs);
+        assert_eq!(6, scheduler.total);
     }
 }
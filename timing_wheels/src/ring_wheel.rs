@@ -0,0 +1,138 @@
+//! A wheel that never allocates, for places where there is no heap or no thread to tick it.
+
+/// A wheel with `N` slots that holds at most one timer per slot.
+///
+/// Timers can expire at most `N` ticks in the future. Actions are plain functions
+/// so they can live inline in the slots, and the wheel is ticked by whoever owns it.
+pub struct RingWheel<const N: usize> {
+    /// The number of ticks since the wheel was created.
+    current_time: u64,
+    slots: [Option<fn()>; N],
+}
+
+/// The reason a timer could not be started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingWheelError {
+    /// Timers have to expire between 1 and `N` ticks in the future.
+    OutOfRange,
+    /// Another timer already expires on the same tick.
+    SlotOccupied,
+}
+
+impl std::fmt::Display for RingWheelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RingWheelError::OutOfRange => write!(f, "timer does not fit in the wheel"),
+            RingWheelError::SlotOccupied => write!(f, "slot is already occupied"),
+        }
+    }
+}
+
+impl std::error::Error for RingWheelError {}
+
+impl<const N: usize> RingWheel<N> {
+    pub const fn new() -> Self {
+        const { assert!(N > 0, "the wheel needs at least one slot") };
+
+        Self {
+            current_time: 0,
+            slots: [None; N],
+        }
+    }
+
+    /// Starts a timer that runs `action` after `expires_in` ticks.
+    ///
+    /// Returns the slot the timer went to.
+    pub fn start_timer(&mut self, expires_in: u64, action: fn()) -> Result<usize, RingWheelError> {
+        if expires_in == 0 || expires_in > N as u64 {
+            return Err(RingWheelError::OutOfRange);
+        }
+
+        let slot = ((self.current_time + expires_in) % N as u64) as usize;
+
+        if self.slots[slot].is_some() {
+            return Err(RingWheelError::SlotOccupied);
+        }
+
+        self.slots[slot] = Some(action);
+
+        Ok(slot)
+    }
+
+    /// Advances the wheel by one tick and runs the action of the timer in the new slot.
+    ///
+    /// Returns true if an action ran.
+    pub fn tick(&mut self) -> bool {
+        self.current_time += 1;
+
+        let slot = (self.current_time % N as u64) as usize;
+
+        match self.slots[slot].take() {
+            None => false,
+            Some(action) => {
+                action();
+                true
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for RingWheel<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn slots_are_reused_once_their_timer_fires() {
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+        fn fire() {
+            FIRED.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mut wheel = RingWheel::<4>::new();
+
+        assert_eq!(Ok(2), wheel.start_timer(2, fire));
+        assert_eq!(Ok(0), wheel.start_timer(4, fire));
+
+        assert!(!wheel.tick());
+        assert!(wheel.tick());
+        assert_eq!(1, FIRED.load(Ordering::SeqCst));
+
+        // Slot 2 is free again, for a timer a whole turn of the wheel later.
+        assert_eq!(Ok(2), wheel.start_timer(4, fire));
+
+        assert!(!wheel.tick());
+        assert!(wheel.tick());
+        assert_eq!(2, FIRED.load(Ordering::SeqCst));
+
+        assert!(!wheel.tick());
+        assert!(wheel.tick());
+        assert_eq!(3, FIRED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn start_timer_fails_when_the_slot_is_taken_or_out_of_range() {
+        let mut wheel = RingWheel::<4>::new();
+
+        assert_eq!(Ok(3), wheel.start_timer(3, || {}));
+        assert_eq!(
+            Err(RingWheelError::SlotOccupied),
+            wheel.start_timer(3, || {})
+        );
+
+        assert_eq!(Err(RingWheelError::OutOfRange), wheel.start_timer(0, || {}));
+        assert_eq!(Err(RingWheelError::OutOfRange), wheel.start_timer(5, || {}));
+
+        assert_eq!(
+            "slot is already occupied",
+            RingWheelError::SlotOccupied.to_string()
+        );
+    }
+}